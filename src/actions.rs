@@ -0,0 +1,276 @@
+use std::{collections::BTreeMap, path::Path};
+
+use bevy::{
+    prelude::*,
+    ecs::system::SystemParam,
+    input::{mouse::{MouseMotion, MouseWheel}, InputSystem},
+    utils::{HashMap, HashSet},
+};
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::options::GameOptions;
+
+/// How fast a gamepad stick held all the way looks around, in mouse pixels per second.
+pub const STICK_LOOK_SPEED: f32 = 600.0;
+
+/// Turns keys, mouse and gamepads into `ActionState` through the `InputBindings` loaded at startup.
+/// Like the input systems that read it, sampling only runs when bevy's input plugin is present.
+pub struct ActionPlugin;
+
+impl Plugin for ActionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InputBindings>()
+            .init_resource::<ActionState>()
+            .add_startup_system(load_bindings.run_if(resource_exists::<Input<KeyCode>>()))
+            .add_system(update_actions
+                .run_if(resource_exists::<Input<KeyCode>>())
+                .in_base_set(CoreSet::PreUpdate)
+                .in_set(ActionSystem)
+                .after(InputSystem));
+    }
+}
+
+/// Where `ActionState` is updated, in `CoreSet::PreUpdate`.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ActionSystem;
+
+/// Something the player does, whatever it is bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Action {
+    /// Walking, as an axis: right in `x`, forward in `y`.
+    Move,
+    /// Turning, as an axis in mouse pixels: right in `x`, up in `y`.
+    Look,
+    Jump,
+    Dig,
+    Place,
+    /// Picks the hotbar slot holding the material of the tile looked at.
+    Interact,
+    CycleCamera,
+    /// Picks the hotbar slot at this index.
+    HotbarSlot(u8),
+    /// Steps through the hotbar, as an axis: back a slot for positive `y`, on a slot for negative.
+    HotbarScroll,
+    GrabCursor,
+    ReleaseCursor,
+}
+
+/// A control an action can be bound to. Buttons drive `pressed`, and the rest drive `axis`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    GamepadButton(GamepadButtonType),
+    /// Four keys making up an axis.
+    Keys { up: KeyCode, down: KeyCode, left: KeyCode, right: KeyCode },
+    MouseMotion,
+    /// The scroll wheel, up in `y`.
+    MouseWheel,
+    /// A stick, or any two gamepad axes.
+    GamepadStick { x: GamepadAxisType, y: GamepadAxisType },
+}
+
+/// What each action is bound to. Any of an action's bindings can trigger it.
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct InputBindings(pub BTreeMap<Action, Vec<Binding>>);
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        let number_keys = [
+            KeyCode::Key1, KeyCode::Key2, KeyCode::Key3,
+            KeyCode::Key4, KeyCode::Key5, KeyCode::Key6,
+            KeyCode::Key7, KeyCode::Key8, KeyCode::Key9,
+        ];
+        let mut bindings = BTreeMap::from([
+            (Action::Move, vec![
+                Binding::Keys { up: KeyCode::W, down: KeyCode::S, left: KeyCode::A, right: KeyCode::D },
+                Binding::GamepadStick { x: GamepadAxisType::LeftStickX, y: GamepadAxisType::LeftStickY },
+            ]),
+            (Action::Look, vec![
+                Binding::MouseMotion,
+                Binding::GamepadStick { x: GamepadAxisType::RightStickX, y: GamepadAxisType::RightStickY },
+            ]),
+            (Action::Jump, vec![Binding::Key(KeyCode::Space), Binding::GamepadButton(GamepadButtonType::South)]),
+            (Action::Dig, vec![Binding::Mouse(MouseButton::Left), Binding::GamepadButton(GamepadButtonType::RightTrigger2)]),
+            (Action::Place, vec![Binding::Mouse(MouseButton::Right), Binding::GamepadButton(GamepadButtonType::LeftTrigger2)]),
+            (Action::Interact, vec![Binding::Key(KeyCode::E), Binding::GamepadButton(GamepadButtonType::West)]),
+            (Action::CycleCamera, vec![Binding::Key(KeyCode::V), Binding::GamepadButton(GamepadButtonType::North)]),
+            (Action::HotbarScroll, vec![Binding::MouseWheel]),
+            (Action::GrabCursor, vec![Binding::Mouse(MouseButton::Left)]),
+            (Action::ReleaseCursor, vec![Binding::Key(KeyCode::Escape)]),
+        ]);
+        bindings.extend((0..).zip(number_keys).map(|(slot, key)| (Action::HotbarSlot(slot), vec![Binding::Key(key)])));
+        Self(bindings)
+    }
+}
+
+impl InputBindings {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+        ron::de::from_bytes(&bytes).map_err(|e| e.to_string())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = ron::ser::to_string_pretty(self, PrettyConfig::new()).map_err(|e| e.to_string())?;
+        std::fs::write(path, text).map_err(|e| e.to_string())
+    }
+}
+
+/// This frame's actions, read by gameplay in place of raw input.
+#[derive(Resource, Debug, Default)]
+pub struct ActionState {
+    pressed: HashSet<Action>,
+    just_pressed: HashSet<Action>,
+    axes: HashMap<Action, Vec2>,
+    /// Actions that stay released until their bindings are let go.
+    consumed: HashSet<Action>,
+}
+
+impl ActionState {
+    pub fn pressed(&self, action: Action) -> bool {
+        self.pressed.contains(&action)
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.just_pressed.contains(&action)
+    }
+
+    pub fn axis(&self, action: Action) -> Vec2 {
+        self.axes.get(&action).copied().unwrap_or_default()
+    }
+
+    /// Releases every action pressed this frame until its bindings are let go, for a press that has
+    /// already been used outside gameplay, like the click that grabs the cursor.
+    pub fn consume_just_pressed(&mut self) {
+        for action in self.just_pressed.drain() {
+            self.pressed.remove(&action);
+            self.consumed.insert(action);
+        }
+    }
+}
+
+/// Reads `GameOptions::bindings`, writing the defaults there if it doesn't exist yet so they can be edited.
+/// Actions a file doesn't mention, such as ones added since it was written, keep their default bindings.
+fn load_bindings(mut bindings: ResMut<InputBindings>, options: Res<GameOptions>) {
+    let path = &options.bindings;
+    if !path.exists() {
+        match bindings.save(path) {
+            Ok(()) => println!("Saved default bindings to {}.", path.display()),
+            Err(e) => println!("Failed to save bindings to {}: {e}", path.display()),
+        }
+        return;
+    }
+    match InputBindings::load(path) {
+        Ok(loaded) => bindings.0.extend(loaded.0),
+        Err(e) => println!("Failed to read bindings {}, using the defaults: {e}", path.display()),
+    }
+}
+
+#[derive(SystemParam)]
+struct RawInput<'w, 's> {
+    keys: Res<'w, Input<KeyCode>>,
+    mouse: Res<'w, Input<MouseButton>>,
+    motion: EventReader<'w, 's, MouseMotion>,
+    wheel: EventReader<'w, 's, MouseWheel>,
+    gamepads: Res<'w, Gamepads>,
+    pad_buttons: Res<'w, Input<GamepadButton>>,
+    pad_axes: Res<'w, Axis<GamepadAxis>>,
+}
+
+impl RawInput<'_, '_> {
+    fn pressed(&self, binding: &Binding) -> bool {
+        match *binding {
+            Binding::Key(key) => self.keys.pressed(key),
+            Binding::Mouse(button) => self.mouse.pressed(button),
+            Binding::GamepadButton(button) => self.gamepads.iter().any(|pad| self.pad_buttons.pressed(GamepadButton::new(pad, button))),
+            _ => false,
+        }
+    }
+
+    fn axis(&self, binding: &Binding, motion: Vec2, wheel: Vec2) -> Vec2 {
+        let key = |k| if self.keys.pressed(k) { 1.0 } else { 0.0 };
+        match *binding {
+            Binding::Keys { up, down, left, right } => Vec2::new(key(right) - key(left), key(up) - key(down)),
+            Binding::MouseMotion => motion,
+            Binding::MouseWheel => wheel,
+            Binding::GamepadStick { x, y } => {
+                let value = |axis| self.gamepads.iter()
+                    .filter_map(|pad| self.pad_axes.get(GamepadAxis::new(pad, axis)))
+                    .sum::<f32>();
+                Vec2::new(value(x), value(y))
+            }
+            _ => Vec2::ZERO,
+        }
+    }
+}
+
+fn update_actions(mut input: RawInput, bindings: Res<InputBindings>, mut state: ResMut<ActionState>, time: Res<Time>) {
+    // Screen Y grows downwards; actions count up as positive.
+    let motion = input.motion.iter().fold(Vec2::ZERO, |sum, m| sum + m.delta * Vec2::new(1.0, -1.0));
+    let wheel = input.wheel.iter().fold(Vec2::ZERO, |sum, w| sum + Vec2::new(w.x, w.y));
+    let dt = time.delta_seconds();
+    let was_pressed = std::mem::take(&mut state.pressed);
+    state.just_pressed.clear();
+    state.axes.clear();
+    for (action, bound) in &bindings.0 {
+        if !bound.iter().any(|b| input.pressed(b)) {
+            state.consumed.remove(action);
+        } else if !state.consumed.contains(action) {
+            state.pressed.insert(*action);
+            if !was_pressed.contains(action) {
+                state.just_pressed.insert(*action);
+            }
+        }
+        let axis = match action {
+            Action::Move => bound.iter().map(|b| input.axis(b, motion, wheel)).sum::<Vec2>().clamp_length_max(1.0),
+            // Sticks are a rate, mouse motion is already a distance.
+            Action::Look => bound.iter().map(|b| match b {
+                Binding::GamepadStick { .. } => input.axis(b, motion, wheel) * STICK_LOOK_SPEED * dt,
+                _ => input.axis(b, motion, wheel),
+            }).sum(),
+            _ => bound.iter().map(|b| input.axis(b, motion, wheel)).sum(),
+        };
+        if axis != Vec2::ZERO {
+            state.axes.insert(*action, axis);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consumed_presses_stay_released_until_let_go() {
+        let mut app = App::new();
+        app.init_resource::<Input<KeyCode>>()
+            .init_resource::<Input<MouseButton>>()
+            .init_resource::<Gamepads>()
+            .init_resource::<Input<GamepadButton>>()
+            .init_resource::<Axis<GamepadAxis>>()
+            .init_resource::<Time>()
+            .add_event::<MouseMotion>()
+            .add_event::<MouseWheel>()
+            .init_resource::<InputBindings>()
+            .init_resource::<ActionState>()
+            .add_system(update_actions);
+        let click = |app: &mut App, down: bool| {
+            let mut mouse = app.world.resource_mut::<Input<MouseButton>>();
+            mouse.clear();
+            if down { mouse.press(MouseButton::Left) } else { mouse.release(MouseButton::Left) }
+            app.update();
+        };
+
+        click(&mut app, true);
+        let mut actions = app.world.resource_mut::<ActionState>();
+        assert!(actions.just_pressed(Action::GrabCursor) && actions.just_pressed(Action::Dig));
+        actions.consume_just_pressed();
+        click(&mut app, true);
+        assert!(!app.world.resource::<ActionState>().pressed(Action::Dig));
+        click(&mut app, false);
+        click(&mut app, true);
+        assert!(app.world.resource::<ActionState>().just_pressed(Action::Dig));
+    }
+}
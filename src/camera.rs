@@ -0,0 +1,112 @@
+use bevy::{prelude::*, render::camera::ScalingMode};
+
+use crate::{
+    actions::{Action, ActionState},
+    dungeon::{Cutaway, Level},
+    player::PLAYER_HALF_EXTENTS,
+    raycast::TileRaycast,
+};
+
+/// Where the camera sits behind the head in `CameraMode::Orbit`: over the right shoulder.
+pub const ORBIT_OFFSET: Vec3 = Vec3::new(0.5, 0.25, 3.0);
+/// How far the orbit camera stays from tiles between it and the head, in world units.
+pub const ORBIT_MARGIN: f32 = 0.2;
+/// Where the camera sits relative to the player in `CameraMode::TopDown`.
+pub const TOP_DOWN_OFFSET: Vec3 = Vec3::new(-10.0, 14.0, 10.0);
+/// How much of the level the top-down view shows from top to bottom, in world units.
+pub const TOP_DOWN_HEIGHT: f32 = 12.0;
+
+/// Places the local player's camera for the current `CameraMode`, which `Action::CycleCamera` steps through.
+pub struct CameraPlugin;
+
+impl Plugin for CameraPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CameraMode>()
+            .add_system(cycle_camera_mode)
+            .add_system(place_camera.after(cycle_camera_mode));
+    }
+}
+
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CameraMode {
+    /// Through the player's eyes.
+    #[default]
+    FirstPerson,
+    /// Over the shoulder, pulled in when tiles are in the way.
+    Orbit,
+    /// An isometric overview, with the tiles above the player cut away.
+    TopDown,
+}
+
+impl CameraMode {
+    pub fn next(self) -> Self {
+        match self {
+            Self::FirstPerson => Self::Orbit,
+            Self::Orbit => Self::TopDown,
+            Self::TopDown => Self::FirstPerson,
+        }
+    }
+}
+
+/// The camera itself, a child of the local player's `CharacterHead`.
+#[derive(Component)]
+pub struct PlayerCamera;
+
+fn cycle_camera_mode(actions: Res<ActionState>, mut mode: ResMut<CameraMode>) {
+    if actions.just_pressed(Action::CycleCamera) {
+        *mode = mode.next();
+    }
+}
+
+fn place_camera(
+    mode: Res<CameraMode>,
+    bodies: Query<&Transform, Without<PlayerCamera>>,
+    heads: Query<(&Parent, &Transform), Without<PlayerCamera>>,
+    mut cameras: Query<(&Parent, &mut Transform, &mut Projection), With<PlayerCamera>>,
+    tiles: TileRaycast,
+    lvl: Res<Level>,
+    mut cutaway: ResMut<Cutaway>,
+) {
+    let mut top = None;
+    for (parent, mut transform, mut projection) in &mut cameras {
+        let Ok((head_parent, head)) = heads.get(parent.get()) else {
+            continue;
+        };
+        let Ok(body) = bodies.get(head_parent.get()) else {
+            continue;
+        };
+        // Worked out from the local transforms rather than last frame's globals, so the camera doesn't lag.
+        let eye = body.mul_transform(*head);
+        let perspective = *mode != CameraMode::TopDown;
+        match (&*projection, perspective) {
+            (Projection::Perspective(_), true) | (Projection::Orthographic(_), false) => {}
+            (_, true) => *projection = Projection::Perspective(default()),
+            (_, false) => {
+                *projection = Projection::Orthographic(OrthographicProjection {
+                    scaling_mode: ScalingMode::FixedVertical(TOP_DOWN_HEIGHT),
+                    ..default()
+                })
+            }
+        }
+        *transform = match *mode {
+            CameraMode::FirstPerson => Transform::IDENTITY,
+            CameraMode::Orbit => {
+                let length = ORBIT_OFFSET.length();
+                let reach = tiles.cast(eye.translation, eye.rotation * ORBIT_OFFSET, length)
+                    .map_or(length, |hit| (hit.distance - ORBIT_MARGIN).max(0.0));
+                Transform::from_translation(ORBIT_OFFSET * reach / length)
+            }
+            CameraMode::TopDown => {
+                let view = Transform::from_translation(body.translation + TOP_DOWN_OFFSET).looking_at(body.translation, Vec3::Y);
+                GlobalTransform::from(view).reparented_to(&GlobalTransform::from(eye))
+            }
+        };
+        if *mode == CameraMode::TopDown {
+            // Keep the layer the player's head is in, and everything below it.
+            top = Some(((body.translation.y + PLAYER_HALF_EXTENTS.y) / lvl.tile_scale).round() as i32);
+        }
+    }
+    if cutaway.0 != top {
+        cutaway.0 = top;
+    }
+}
@@ -1,73 +1,217 @@
 use bevy::prelude::*;
-use bevy_rapier3d::prelude::KinematicCharacterController;
+use bevy_rapier3d::prelude::{KinematicCharacterController, KinematicCharacterControllerOutput, RapierConfiguration};
+
+use crate::sim::SimSet;
+
+/// How long after walking off a ledge a jump still works, in seconds.
+pub const COYOTE_TIME: f32 = 0.1;
+/// How long a jump pressed in the air is remembered for landing, in seconds.
+pub const JUMP_BUFFER: f32 = 0.15;
 
 pub struct CharacterPlugin;
 
 impl Plugin for CharacterPlugin {
     fn build(&self, app: &mut App) {
         app
-            .add_system(char_accel_movement_aim)
-            .add_system(char_accel_movement_update);
+            .add_systems((char_accel_movement_aim, char_accel_movement_update)
+                .in_base_set(SimSet::Gameplay)
+                .in_schedule(CoreSchedule::FixedUpdate));
     }
 }
 
-#[derive(Component, Default)]
+/// Furthest a head pitches up or down, in radians.
+pub const MAX_PITCH: f32 = 89.0 * std::f32::consts::PI / 180.0;
+
+#[derive(Component, Default, Clone)]
 pub struct CharacterMovement {
+    /// Direction to move in this tick, at most unit length.
     pub requested: Option<Vec3>,
+    /// Radians to turn this tick: yaw left in `x`, pitch up in `y`.
     pub aim_requested: Option<Vec2>,
+    /// How quickly the character reaches `max_speed` while moving, per second.
     pub acceleration: f32,
-    pub dampening: f32,
+    /// How quickly the character stops without a request, per second.
+    pub friction: f32,
+    /// Share of `acceleration` and `friction` that applies in the air.
+    pub air_control: f32,
+    /// Horizontal velocity, in units per second.
     pub velocity: Vec3,
     pub max_speed: f32,
-    pub min_threshold: f32,
+    /// Set from the character controller after each move.
     pub grounded: bool,
+    /// Speed along Y from gravity and jumps, in units per second.
+    pub vertical_velocity: f32,
+    pub jump_speed: f32,
+    pub jump_requested: bool,
+    /// Seconds since the character last stood on something.
+    pub since_grounded: f32,
+    /// Seconds left for a requested jump to happen.
+    pub jump_buffer: f32,
 }
 
+/// A child of a character that pitches up and down, such as its camera, while the body only yaws.
 #[derive(Component, Default)]
-pub struct CharacterHead;
+pub struct CharacterHead {
+    /// Radians above the horizon, within `MAX_PITCH`.
+    pub pitch: f32,
+}
 
+/// Yaws bodies about Y and pitches their heads about their own X.
 pub fn char_accel_movement_aim(
     mut characters: Query<(&mut Transform, &mut CharacterMovement), Without<CharacterHead>>,
-    mut heads: Query<(&Parent, &mut Transform), With<CharacterHead>>,
-    time: Res<Time>
+    mut heads: Query<(&Parent, &mut Transform, &mut CharacterHead)>,
 ) {
-    for (parent, mut head_transform) in &mut heads {
+    for (parent, mut head_transform, mut head) in &mut heads {
         if let Ok((_, movement)) = characters.get(parent.get()) {
             if let Some(aim) = movement.aim_requested {
-                println!("char_accel_movement_aim (head)");
-                head_transform.rotate_local_x(aim.y * time.delta_seconds());
+                head.pitch = (head.pitch + aim.y).clamp(-MAX_PITCH, MAX_PITCH);
+                head_transform.rotation = Quat::from_rotation_x(head.pitch);
             }
         }
     }
     for (mut transform, mut movement) in &mut characters {
-        if let Some(aim) = movement.aim_requested {
-            println!("char_accel_movement_aim (body)");
-            transform.rotate_z(aim.x * time.delta_seconds());
-            movement.aim_requested = None;
+        if let Some(aim) = movement.aim_requested.take() {
+            transform.rotate_y(aim.x);
         }
     }
 }
 
+/// Advances a horizontal `velocity` by `dt` towards `wish * max_speed`, returning the new velocity and the
+/// distance covered. Velocity closes on its target exponentially and that curve is integrated exactly, so
+/// a second of ticks covers the same distance whatever the tick rate.
+pub fn step_horizontal(velocity: Vec3, wish: Vec3, movement: &CharacterMovement, grounded: bool, dt: f32) -> (Vec3, Vec3) {
+    let target = wish.clamp_length_max(1.0) * movement.max_speed;
+    let rate = if wish == Vec3::ZERO { movement.friction } else { movement.acceleration };
+    let rate = if grounded { rate } else { rate * movement.air_control };
+    if rate <= 0.0 {
+        return (velocity, velocity * dt);
+    }
+    let offset = velocity - target;
+    let decay = (-rate * dt).exp();
+    (target + offset * decay, target * dt + offset * (1.0 - decay) / rate)
+}
+
+/// Advances a vertical speed by `dt` under constant `gravity`, returning the new speed and the distance covered.
+pub fn step_vertical(velocity: f32, gravity: f32, dt: f32) -> (f32, f32) {
+    (velocity + gravity * dt, velocity * dt + 0.5 * gravity * dt * dt)
+}
+
 pub fn char_accel_movement_update(
-    mut characters: Query<(&mut KinematicCharacterController, &mut CharacterMovement)>,
-    time: Res<Time>
+    mut characters: Query<(&mut KinematicCharacterController, &mut CharacterMovement, Option<&KinematicCharacterControllerOutput>)>,
+    time: Res<FixedTime>,
+    physics: Res<RapierConfiguration>,
 ) {
-    for (mut character, mut movement) in &mut characters {
-        if let Some(acceleration) = movement.requested {
-            movement.velocity = movement.velocity + (acceleration * time.delta_seconds());
-            movement.requested = None;
-        } else {
-            if movement.velocity != Vec3::ZERO {
-                movement.velocity = movement.velocity.lerp(movement.velocity / (1.0 + movement.dampening), time.delta_seconds());
-                if movement.velocity.length() < movement.min_threshold {
-                    movement.velocity = Vec3::ZERO;
-                }
+    let dt = time.period.as_secs_f32();
+    for (mut character, mut movement, output) in &mut characters {
+        movement.grounded = output.is_some_and(|o| o.grounded);
+        // Always move, even if only downwards, so the controller keeps reporting whether we're grounded.
+        character.translation = Some(step_character(&mut movement, dt, physics.gravity.y));
+    }
+}
+
+/// Runs one tick of a character's requests, returning the translation to hand to its controller.
+/// `grounded` should already hold what the controller reported after the last move.
+pub fn step_character(movement: &mut CharacterMovement, dt: f32, gravity: f32) -> Vec3 {
+    let wish = movement.requested.take().map_or(Vec3::ZERO, |r| Vec3::new(r.x, 0.0, r.z));
+    let (velocity, horizontal) = step_horizontal(movement.velocity, wish, movement, movement.grounded, dt);
+    movement.velocity = velocity;
+
+    // Kinematic bodies ignore gravity, so falling and jumping are integrated here.
+    if movement.grounded {
+        movement.since_grounded = 0.0;
+        movement.vertical_velocity = movement.vertical_velocity.max(0.0);
+    } else {
+        movement.since_grounded += dt;
+    }
+    if movement.jump_requested {
+        movement.jump_requested = false;
+        movement.jump_buffer = JUMP_BUFFER;
+    }
+    if movement.jump_buffer > 0.0 && movement.since_grounded <= COYOTE_TIME {
+        movement.vertical_velocity = movement.jump_speed;
+        movement.jump_buffer = 0.0;
+        // No second jump until landing again.
+        movement.since_grounded = f32::INFINITY;
+    }
+    movement.jump_buffer = (movement.jump_buffer - dt).max(0.0);
+    let (vertical_velocity, vertical) = step_vertical(movement.vertical_velocity, gravity, dt);
+    movement.vertical_velocity = vertical_velocity;
+    horizontal + Vec3::Y * vertical
+}
+
+/// A character that walks at 3 units a second, for tests.
+#[cfg(test)]
+pub(crate) fn walker() -> CharacterMovement {
+    CharacterMovement { acceleration: 10.0, friction: 12.0, air_control: 0.2, max_speed: 3.0, ..default() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATES: [u32; 3] = [30, 60, 144];
+
+    /// Velocity and distance after a second of ticks at `rate`, starting at `velocity`.
+    fn second_of_horizontal(rate: u32, velocity: Vec3, wish: Vec3, grounded: bool) -> (Vec3, Vec3) {
+        let dt = 1.0 / rate as f32;
+        (0..rate).fold((velocity, Vec3::ZERO), |(velocity, distance), _| {
+            let (velocity, moved) = step_horizontal(velocity, wish, &walker(), grounded, dt);
+            (velocity, distance + moved)
+        })
+    }
+
+    fn assert_close(a: Vec3, b: Vec3, what: &str) {
+        assert!((a - b).length() < 1e-3, "{what}: {a} != {b}");
+    }
+
+    #[test]
+    fn horizontal_steps_agree_across_tick_rates() {
+        let cases = [
+            ("accelerating", Vec3::ZERO, Vec3::new(0.6, 0.0, -0.8), true),
+            ("stopping", Vec3::new(3.0, 0.0, 0.0), Vec3::ZERO, true),
+            ("turning in the air", Vec3::new(0.0, 0.0, 3.0), Vec3::X, false),
+        ];
+        for (what, velocity, wish, grounded) in cases {
+            let (v60, d60) = second_of_horizontal(60, velocity, wish, grounded);
+            for rate in RATES {
+                let (v, d) = second_of_horizontal(rate, velocity, wish, grounded);
+                assert_close(v, v60, &format!("{what} velocity at {rate} Hz"));
+                assert_close(d, d60, &format!("{what} distance at {rate} Hz"));
             }
         }
-        if movement.velocity != Vec3::ZERO {
-            println!("char_accel_movement_update");
-            movement.requested = None;
-            character.translation = Some(movement.velocity);
+    }
+
+    #[test]
+    fn vertical_steps_agree_across_tick_rates() {
+        for rate in RATES {
+            let dt = 1.0 / rate as f32;
+            let (velocity, height) = (0..rate).fold((4.5, 0.0), |(velocity, height), _| {
+                let (velocity, moved) = step_vertical(velocity, -9.8, dt);
+                (velocity, height + moved)
+            });
+            // A jump at 4.5 under 9.8 of gravity: v = 4.5 - 9.8t, h = 4.5t - 4.9t².
+            assert!((velocity - (4.5 - 9.8)).abs() < 1e-3, "velocity at {rate} Hz: {velocity}");
+            assert!((height - (4.5 - 4.9)).abs() < 1e-3, "height at {rate} Hz: {height}");
+        }
+    }
+
+    #[test]
+    fn characters_cover_the_same_ground_across_tick_rates() {
+        let second = |rate: u32| {
+            let mut movement = walker();
+            let mut moved = Vec3::ZERO;
+            for _ in 0..rate {
+                movement.grounded = true;
+                movement.requested = Some(Vec3::NEG_Z);
+                moved += step_character(&mut movement, 1.0 / rate as f32, -9.8);
+            }
+            (moved * Vec3::new(1.0, 0.0, 1.0), movement.velocity)
+        };
+        let (d60, v60) = second(60);
+        for rate in RATES {
+            let (d, v) = second(rate);
+            assert_close(d, d60, &format!("distance at {rate} Hz"));
+            assert_close(v, v60, &format!("velocity at {rate} Hz"));
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,103 @@
+use bevy::prelude::*;
+
+pub const CHUNK_SIZE: i32 = 16;
+pub const CHUNK_VOLUME: usize = (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+/// A fixed 16x16x16 block of tiles, addressed by local coordinates.
+/// Each tile holds the id of its `MaterialType`, or `None` when empty.
+pub struct Chunk {
+    tiles: Vec<Option<usize>>,
+    /// How many tiles are filled, kept up to date by `set`.
+    count: usize,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self {
+            tiles: vec![None; CHUNK_VOLUME],
+            count: 0,
+        }
+    }
+}
+
+impl Chunk {
+    fn index(local: IVec3) -> Option<usize> {
+        if local.cmplt(IVec3::ZERO).any() || local.cmpge(IVec3::splat(CHUNK_SIZE)).any() {
+            return None;
+        }
+        Some((local.x + local.y * CHUNK_SIZE + local.z * CHUNK_SIZE * CHUNK_SIZE) as usize)
+    }
+
+    pub fn get(&self, local: IVec3) -> Option<usize> {
+        Self::index(local).and_then(|i| self.tiles[i])
+    }
+
+    /// Replaces the tile at `local`, returning what was there before.
+    pub fn set(&mut self, local: IVec3, tile: Option<usize>) -> Option<usize> {
+        let i = Self::index(local)?;
+        let old = std::mem::replace(&mut self.tiles[i], tile);
+        match (old, tile) {
+            (None, Some(_)) => self.count += 1,
+            (Some(_), None) => self.count -= 1,
+            _ => {}
+        }
+        old
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (IVec3, usize)> + '_ {
+        self.tiles.iter().enumerate().filter_map(|(i, t)| t.map(|t| (local_from_index(i), t)))
+    }
+}
+
+fn local_from_index(i: usize) -> IVec3 {
+    let i = i as i32;
+    IVec3::new(i % CHUNK_SIZE, (i / CHUNK_SIZE) % CHUNK_SIZE, i / (CHUNK_SIZE * CHUNK_SIZE))
+}
+
+/// Coordinate of the chunk containing the tile at `pos`.
+pub fn chunk_coord(pos: IVec3) -> IVec3 {
+    IVec3::new(
+        pos.x.div_euclid(CHUNK_SIZE),
+        pos.y.div_euclid(CHUNK_SIZE),
+        pos.z.div_euclid(CHUNK_SIZE),
+    )
+}
+
+/// Position of the tile at `pos` inside its chunk.
+pub fn local_coord(pos: IVec3) -> IVec3 {
+    IVec3::new(
+        pos.x.rem_euclid(CHUNK_SIZE),
+        pos.y.rem_euclid(CHUNK_SIZE),
+        pos.z.rem_euclid(CHUNK_SIZE),
+    )
+}
+
+/// Tile position of the chunk's origin.
+pub fn chunk_origin(coord: IVec3) -> IVec3 {
+    coord * CHUNK_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emptiness_follows_sets_and_clears() {
+        let mut chunk = Chunk::default();
+        assert!(chunk.is_empty());
+        chunk.set(IVec3::new(1, 2, 3), Some(0));
+        chunk.set(IVec3::new(1, 2, 3), Some(1));
+        chunk.set(IVec3::new(4, 5, 6), Some(0));
+        chunk.set(IVec3::new(7, 7, 7), None);
+        chunk.set(IVec3::new(CHUNK_SIZE, 0, 0), Some(0));
+        assert!(!chunk.is_empty());
+        chunk.set(IVec3::new(1, 2, 3), None);
+        assert!(!chunk.is_empty());
+        chunk.set(IVec3::new(4, 5, 6), None);
+        assert!(chunk.is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::Collider;
+
+use crate::chunk::{Chunk, CHUNK_SIZE, CHUNK_VOLUME};
+
+/// An axis-aligned run of solid tiles inside a chunk, in tile units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileBox {
+    pub min: IVec3,
+    pub size: IVec3,
+}
+
+impl TileBox {
+    /// Centre of the box relative to the chunk's origin corner.
+    pub fn center(&self, tile_scale: f32) -> Vec3 {
+        (self.min.as_vec3() + self.size.as_vec3() / 2.0) * tile_scale
+    }
+
+    /// Rapier cuboids are sized by half their extent along each axis.
+    pub fn half_extents(&self, tile_scale: f32) -> Vec3 {
+        self.size.as_vec3() * tile_scale / 2.0
+    }
+}
+
+/// Greedily merges the chunk's tiles whose material passes `include` into as few boxes as possible,
+/// growing each box along X, then Y, then Z.
+pub fn merge_boxes(chunk: &Chunk, include: impl Fn(usize) -> bool) -> Vec<TileBox> {
+    let n = CHUNK_SIZE;
+    let index = |p: IVec3| (p.x + p.y * n + p.z * n * n) as usize;
+    let is_solid = |p: IVec3| chunk.get(p).is_some_and(&include);
+    let mut used = vec![false; CHUNK_VOLUME];
+    let mut boxes = Vec::new();
+    for z in 0..n {
+        for y in 0..n {
+            for x in 0..n {
+                let min = IVec3::new(x, y, z);
+                if used[index(min)] || !is_solid(min) {
+                    continue;
+                }
+                let free = |p: IVec3| !used[index(p)] && is_solid(p);
+                let mut size = IVec3::ONE;
+                while min.x + size.x < n && free(min + IVec3::X * size.x) {
+                    size.x += 1;
+                }
+                while min.y + size.y < n && (0..size.x).all(|dx| free(min + IVec3::new(dx, size.y, 0))) {
+                    size.y += 1;
+                }
+                while min.z + size.z < n
+                    && (0..size.y).all(|dy| (0..size.x).all(|dx| free(min + IVec3::new(dx, dy, size.z))))
+                {
+                    size.z += 1;
+                }
+                for dz in 0..size.z {
+                    for dy in 0..size.y {
+                        for dx in 0..size.x {
+                            used[index(min + IVec3::new(dx, dy, dz))] = true;
+                        }
+                    }
+                }
+                boxes.push(TileBox { min, size });
+            }
+        }
+    }
+    boxes
+}
+
+/// Combines the boxes into one compound collider positioned relative to the chunk's origin corner.
+pub fn chunk_collider(boxes: &[TileBox], tile_scale: f32) -> Option<Collider> {
+    if boxes.is_empty() {
+        return None;
+    }
+    let shapes = boxes.iter().map(|b| {
+        let half = b.half_extents(tile_scale);
+        (b.center(tile_scale), Quat::IDENTITY, Collider::cuboid(half.x, half.y, half.z))
+    }).collect();
+    Some(Collider::compound(shapes))
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_rapier3d::prelude::ColliderView;
+
+    use super::*;
+
+    fn chunk_with(tiles: impl IntoIterator<Item = IVec3>) -> Chunk {
+        let mut chunk = Chunk::default();
+        for pos in tiles {
+            chunk.set(pos, Some(0));
+        }
+        chunk
+    }
+
+    #[test]
+    fn a_block_of_tiles_merges_into_one_box() {
+        let tiles = (0..3).flat_map(|x| (0..2).flat_map(move |y| (0..4).map(move |z| IVec3::new(x, y, z) + IVec3::ONE)));
+        let boxes = merge_boxes(&chunk_with(tiles), |_| true);
+        assert_eq!(boxes, vec![TileBox { min: IVec3::ONE, size: IVec3::new(3, 2, 4) }]);
+    }
+
+    #[test]
+    fn excluded_materials_split_boxes() {
+        let mut chunk = chunk_with((0..5).map(|x| IVec3::new(x, 0, 0)));
+        chunk.set(IVec3::new(2, 0, 0), Some(1));
+        let boxes = merge_boxes(&chunk, |id| id == 0);
+        assert_eq!(boxes, vec![
+            TileBox { min: IVec3::ZERO, size: IVec3::new(2, 1, 1) },
+            TileBox { min: IVec3::new(3, 0, 0), size: IVec3::new(2, 1, 1) },
+        ]);
+    }
+
+    #[test]
+    fn cuboids_have_half_extents_and_centres_in_world_units() {
+        let boxes = [TileBox { min: IVec3::new(2, 0, 4), size: IVec3::new(3, 2, 1) }];
+        let collider = chunk_collider(&boxes, 0.5).unwrap();
+        let compound = collider.as_compound().unwrap();
+        let shapes: Vec<_> = compound.shapes().collect();
+        assert_eq!(shapes.len(), 1);
+        let (position, rotation, ColliderView::Cuboid(cuboid)) = &shapes[0] else {
+            panic!("expected a cuboid");
+        };
+        assert_eq!(*position, Vec3::new(1.75, 0.5, 2.25));
+        assert_eq!(*rotation, Quat::IDENTITY);
+        assert_eq!(cuboid.half_extents(), Vec3::new(0.75, 0.5, 0.25));
+    }
+
+    #[test]
+    fn no_boxes_means_no_collider() {
+        assert!(chunk_collider(&[], 0.5).is_none());
+    }
+}
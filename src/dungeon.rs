@@ -1,16 +1,18 @@
-use std::collections::HashMap;
+use std::{collections::{HashMap, HashSet}, path::PathBuf};
 
-use bevy::prelude::*;
-use bevy_rapier3d::prelude::{RigidBody, Collider};
-use ndarray::Array3;
-use rltk::FastNoise;
+use bevy::{prelude::*, ecs::system::SystemParam, tasks::{AsyncComputeTaskPool, Task}};
+use bevy_rapier3d::prelude::{RigidBody, Collider, Sensor, ActiveCollisionTypes};
+use serde::{Deserialize, Serialize};
 
-use crate::{GameAssets, tileset_1bit::TileSet1Bit};
+use crate::{chunk::{Chunk, chunk_coord, local_coord, chunk_origin, CHUNK_SIZE}, collider::{chunk_collider, merge_boxes}, mesher::{greedy_mesh, opaque_face, translucent_face}, generator::{DungeonPalette, GenProgress, GeneratorConfig, TileGrid}, material::{MaterialTypes, MaterialType}, level_file::LevelSnapshot, player::move_players_to_spawn, sim::SimSet};
 
 pub const LEVEL_SIZE_X: usize = 32;
-pub const LEVEL_SIZE_Y: usize = 8;
+/// Two storeys of `generator::FLOOR_HEIGHT`, so the default level has a staircase between floors.
+pub const LEVEL_SIZE_Y: usize = 16;
 pub const LEVEL_SIZE_Z: usize = 32;
 pub const LVL_S_C: usize = LEVEL_SIZE_X * LEVEL_SIZE_Y * LEVEL_SIZE_Z;
+/// Smallest level, in tiles, that every generator can build.
+pub const MIN_LEVEL_SIZE: [usize; 3] = [8, 4, 8];
 
 pub struct LvlPlugin;
 
@@ -18,87 +20,247 @@ impl Plugin for LvlPlugin {
     fn build(&self, app: &mut App) {
         app.add_state::<LvlState>()
             .init_resource::<Level>()
-            .init_resource::<MaterialTypes>()
-            .add_system(spawn_tile)
-            .add_system(destroy_tile)
-            .add_system(destroy_tile_rect)
-            .add_system(gen_dungeon_init);
+            .init_resource::<LvlProgress>()
+            .init_resource::<LvlGeneration>()
+            .add_event::<ChunkChanged>()
+            .add_event::<TileEdit>()
+            // Loads run last, so a level loaded in the same frame as an init replaces the generation it started.
+            .add_systems((gen_dungeon_init, poll_generation.after(gen_dungeon_init), load_level.after(poll_generation), load_snapshot.after(poll_generation))
+                .in_set(LvlSet::Edit)
+                .in_set(OnUpdate(LvlState::Generate)))
+            // Chained so a tick's tile commands always apply in the same order: spawns, then destroys.
+            .add_systems((spawn_tile, destroy_tile, destroy_tile_rect)
+                .chain()
+                .distributive_run_if(level_settled)
+                .in_base_set(SimSet::Gameplay)
+                .in_set(LvlSet::Edit)
+                .in_schedule(CoreSchedule::FixedUpdate))
+            .add_systems((save_level, request_regenerate).in_set(LvlSet::Edit).in_set(OnUpdate(LvlState::Ready)))
+            .add_system(clean_level.in_schedule(OnEnter(LvlState::Clean)))
+            .add_system(move_players_to_spawn.in_schedule(OnEnter(LvlState::Ready)))
+            .add_system(sync_chunk_entities.after(LvlSet::Edit).before(LvlSet::Build))
+            // New chunk entities must exist before the build systems look them up.
+            .add_system(apply_system_buffers.after(sync_chunk_entities).before(LvlSet::Build))
+            .add_system(build_chunk_colliders.in_set(LvlSet::Build));
+    }
+}
+
+/// Meshes chunks as they change. Headless runs leave it out and only build colliders.
+pub struct LvlRenderPlugin;
+
+impl Plugin for LvlRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChunkMaterial>()
+            .init_resource::<Cutaway>()
+            .add_system(mesh_dirty_chunks.in_set(LvlSet::Build));
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemSet)]
+pub enum LvlSet {
+    /// Systems that change tiles in the `Level`.
+    Edit,
+    /// Systems that rebuild chunk meshes and colliders after edits.
+    Build,
+}
+
+/// Tiles above this Y are left out of chunk meshes, so the level can be seen into from above.
+/// Colliders keep every tile.
+#[derive(Resource, Debug, Default, PartialEq, Eq)]
+pub struct Cutaway(pub Option<i32>);
+
+/// Sent once per frame for every chunk whose tiles changed and which still has an entity.
+pub struct ChunkChanged {
+    pub coord: IVec3,
+    pub entity: Entity,
+}
+
+/// Lifecycle of the level: it is built in `Generate`, played in `Ready`,
+/// and torn down in `Clean` before another level is generated or loaded.
 #[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, States)]
-enum LvlState {
+pub enum LvlState {
     #[default]
     Generate,
     Ready,
     Clean,
 }
 
+/// Tile storage for the level, split into chunks that are allocated on demand.
+/// `size` is only the region filled by generation; tiles may be set anywhere.
 #[derive(Resource)]
 pub struct Level {
-    pub tiles: Array3<Option<Entity>>,
+    pub chunks: HashMap<IVec3, Chunk>,
+    /// Rendered entity for each chunk that currently has a mesh.
+    pub chunk_entities: HashMap<IVec3, Entity>,
+    /// Chunks whose tiles changed since they were last built.
+    pub dirty: HashSet<IVec3>,
     pub size: [usize; 3],
     pub tile_scale: f32,
+    pub spawns: Vec<LevelSpawn>,
+}
+
+/// A named point in tile coordinates where something should appear, e.g. `"Player"`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelSpawn {
+    pub name: String,
+    pub pos: IVec3,
+}
+
+impl LevelSpawn {
+    pub fn player(pos: IVec3) -> Self {
+        Self {
+            name: "Player".to_owned(),
+            pos,
+        }
+    }
 }
 
 impl Default for Level {
     fn default() -> Self {
-        let size = [LEVEL_SIZE_X, LEVEL_SIZE_Y, LEVEL_SIZE_Z];
         Self {
-            tiles: Array3::from_shape_simple_fn(size, || None),
-            size,
+            chunks: HashMap::new(),
+            chunk_entities: HashMap::new(),
+            dirty: HashSet::new(),
+            size: [LEVEL_SIZE_X, LEVEL_SIZE_Y, LEVEL_SIZE_Z],
             tile_scale: 0.5,
+            spawns: Vec::new(),
         }
     }
 }
 
-pub struct MaterialType {
-    pub id: usize,
-    pub name: String,
-    pub mesh: Handle<Mesh>,
-    pub material: Handle<StandardMaterial>,
+impl Level {
+    pub fn get(&self, pos: IVec3) -> Option<usize> {
+        self.chunks.get(&chunk_coord(pos)).and_then(|c| c.get(local_coord(pos)))
+    }
+
+    /// Replaces the tile at `pos`, returning what was there before.
+    /// Chunks are created as needed and dropped again once emptied.
+    pub fn set(&mut self, pos: IVec3, tile: Option<usize>) -> Option<usize> {
+        let coord = chunk_coord(pos);
+        let local = local_coord(pos);
+        let prev = match (self.chunks.get_mut(&coord), tile) {
+            (Some(chunk), _) => chunk.set(local, tile),
+            (None, Some(_)) => self.chunks.entry(coord).or_default().set(local, tile),
+            (None, None) => None,
+        };
+        if tile.is_none() && self.chunks.get(&coord).is_some_and(Chunk::is_empty) {
+            self.chunks.remove(&coord);
+        }
+        if prev != tile {
+            self.mark_dirty(coord, local);
+        }
+        prev
+    }
+
+    /// Flags the chunk for rebuilding, along with any neighbour whose faces touch `local`.
+    fn mark_dirty(&mut self, coord: IVec3, local: IVec3) {
+        self.dirty.insert(coord);
+        for axis in 0..3 {
+            let mut step = IVec3::ZERO;
+            step[axis] = 1;
+            if local[axis] == 0 {
+                self.dirty.insert(coord - step);
+            } else if local[axis] == CHUNK_SIZE - 1 {
+                self.dirty.insert(coord + step);
+            }
+        }
+    }
+
+    /// Removes every tile and spawn. Chunk entities are despawned on the next rebuild.
+    pub fn clear(&mut self) {
+        let coords: Vec<IVec3> = self.chunks.keys().copied().collect();
+        self.dirty.extend(coords);
+        self.chunks.clear();
+        self.spawns.clear();
+    }
+
+    /// Inclusive bounds of all stored tiles, or `None` if the level is empty.
+    pub fn bounds(&self) -> Option<(IVec3, IVec3)> {
+        self.iter().fold(None, |acc, (pos, _)| match acc {
+            None => Some((pos, pos)),
+            Some((min, max)) => Some((min.min(pos), max.max(pos))),
+        })
+    }
+
+    /// Flags every chunk for rebuilding, e.g. after materials change.
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty.extend(self.chunks.keys().copied());
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (IVec3, usize)> + '_ {
+        self.chunks.iter().flat_map(|(coord, chunk)| {
+            let origin = chunk_origin(*coord);
+            chunk.iter().map(move |(local, tile)| (origin + local, tile))
+        })
+    }
 }
 
+/// Shared materials for all chunk meshes; tile colours come from the mesh's vertex colours.
 #[derive(Resource)]
-pub struct MaterialTypes {
-    pub map: HashMap<usize, MaterialType>,
+pub struct ChunkMaterial {
+    pub opaque: Handle<StandardMaterial>,
+    pub translucent: Handle<StandardMaterial>,
 }
 
-impl FromWorld for MaterialTypes {
+impl FromWorld for ChunkMaterial {
     fn from_world(world: &mut World) -> Self {
-        let mesh = {
-            let mut meshes = world.get_resource_mut::<Assets<Mesh>>().unwrap();
-            meshes.add(Mesh::from(shape::Cube { size: 0.5 }))
-        };
-        let (stone_mat, dirt_mat, wood_mat) = {
-            let mut materials = world.get_resource_mut::<Assets<StandardMaterial>>().unwrap();
-            (materials.add(Color::GRAY.into()), materials.add(Color::BEIGE.into()), materials.add(Color::BISQUE.into()))
-        };
+        let mut materials = world.resource_mut::<Assets<StandardMaterial>>();
         Self {
-            map: HashMap::from([
-                (0, MaterialType { id: 0, name: "Stone".to_owned(), mesh: mesh.clone(), material: stone_mat }),
-                (1, MaterialType { id: 1, name: "Dirt".to_owned(), mesh: mesh.clone(), material: dirt_mat }),
-                (2, MaterialType { id: 2, name: "Wood".to_owned(), mesh: mesh.clone(), material: wood_mat }),
-            ]),
+            opaque: materials.add(StandardMaterial {
+                base_color: Color::WHITE,
+                perceptual_roughness: 0.9,
+                ..default()
+            }),
+            translucent: materials.add(StandardMaterial {
+                base_color: Color::WHITE,
+                perceptual_roughness: 0.2,
+                alpha_mode: AlphaMode::Blend,
+                ..default()
+            }),
         }
     }
 }
 
-impl MaterialTypes {
-    pub fn get_mat(&self, name: &str) -> Option<&MaterialType> {
-        self.map.values().find(|v| v.name == name)
-    }
-}
-
 #[derive(Component)]
 pub struct Active;
 
-#[derive(Component)]
-pub struct Tile;
+/// Parts of a chunk that live on their own child entity, so each carries only the markers that apply to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChunkLayer {
+    OpaqueMesh,
+    TranslucentMesh,
+    Solid,
+    Liquid,
+    Climbable,
+    Hazardous,
+}
 
+/// Marks the entity that holds the chunk at `coord`, with one child per non-empty layer.
 #[derive(Component)]
-pub struct Material {
-    pub id: usize,
+pub struct TileChunk {
+    pub coord: IVec3,
+    pub layers: HashMap<ChunkLayer, Entity>,
+}
+
+impl TileChunk {
+    /// Puts `bundle` on the child for `layer`, spawning it if needed, or despawns the child when there is no bundle.
+    fn set_layer(&mut self, commands: &mut Commands, parent: Entity, layer: ChunkLayer, bundle: Option<impl Bundle>) {
+        match (bundle, self.layers.get(&layer)) {
+            (Some(bundle), Some(child)) => {
+                commands.entity(*child).insert(bundle);
+            }
+            (Some(bundle), None) => {
+                let child = commands.spawn(bundle).id();
+                commands.entity(parent).add_child(child);
+                self.layers.insert(layer, child);
+            }
+            (None, Some(child)) => {
+                commands.entity(*child).despawn_recursive();
+                self.layers.remove(&layer);
+            }
+            (None, None) => {}
+        }
+    }
 }
 
 #[derive(Component)]
@@ -107,48 +269,192 @@ pub struct Opaque;
 #[derive(Component)]
 pub struct Solid;
 
-#[derive(Bundle)]
-pub struct TileBundle {
-    pub tile: Tile,
-    pub active: Active,
-    pub opaque: Opaque,
-    pub solid: Solid,
-    pub pbr: PbrBundle,
-    pub mat: Material,
-    pub body: RigidBody,
-    pub colllider: Collider,
-}
-
-impl TileBundle {
-    pub fn new(position: [usize; 3], mat: &MaterialType, tile_scale: f32) -> Self {
-        let world_pos = [position[0] as f32 * tile_scale, position[1] as f32 * tile_scale, position[2] as f32 * tile_scale];
-        Self { tile: Tile, active: Active, opaque: Opaque, solid: Solid,
-            pbr: PbrBundle {
-                mesh: mat.mesh.clone(),
-                material: mat.material.clone(),
-                transform: Transform::from_translation(world_pos.into()),
+#[derive(Component)]
+pub struct Liquid;
+
+#[derive(Component)]
+pub struct Climbable;
+
+#[derive(Component)]
+pub struct Hazardous;
+
+/// Spawns or despawns chunk entities to match the level and announces which chunks need rebuilding.
+fn sync_chunk_entities(mut commands: Commands, mut lvl: ResMut<Level>, mut changed: EventWriter<ChunkChanged>) {
+    let dirty: Vec<IVec3> = lvl.dirty.drain().collect();
+    for coord in dirty {
+        if !lvl.chunks.contains_key(&coord) {
+            if let Some(ent) = lvl.chunk_entities.remove(&coord) {
+                commands.entity(ent).despawn_recursive();
+            }
+            continue;
+        }
+        let entity = match lvl.chunk_entities.get(&coord) {
+            Some(ent) => *ent,
+            None => {
+                // Tile centres sit on multiples of the tile scale, so the chunk's corner is half a tile back.
+                let translation = chunk_origin(coord).as_vec3() * lvl.tile_scale - Vec3::splat(lvl.tile_scale / 2.0);
+                let ent = commands.spawn((
+                    TileChunk { coord, layers: HashMap::new() },
+                    SpatialBundle::from_transform(Transform::from_translation(translation)),
+                    RigidBody::Fixed,
+                )).id();
+                lvl.chunk_entities.insert(coord, ent);
+                ent
+            }
+        };
+        changed.send(ChunkChanged { coord, entity });
+    }
+}
+
+fn mesh_dirty_chunks(
+    mut commands: Commands,
+    mut changed: EventReader<ChunkChanged>,
+    mut chunks: Query<(Entity, &mut TileChunk)>,
+    lvl: Res<Level>,
+    mats: Res<MaterialTypes>,
+    (chunk_mat, cutaway, mut meshed_cut): (Res<ChunkMaterial>, Res<Cutaway>, Local<Option<i32>>),
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    let mut dirty: Vec<(IVec3, Entity)> = changed.iter().map(|c| (c.coord, c.entity)).collect();
+    if cutaway.0 != *meshed_cut {
+        let (old, new) = (*meshed_cut, cutaway.0);
+        dirty.extend(chunks.iter()
+            .filter(|(_, chunk)| cut_crosses(chunk.coord, old, new))
+            .map(|(entity, chunk)| (chunk.coord, entity)));
+        dirty.sort_unstable_by_key(|(_, entity)| *entity);
+        dirty.dedup_by_key(|(_, entity)| *entity);
+        *meshed_cut = cutaway.0;
+    }
+    for (coord, entity) in &dirty {
+        let Ok((_, mut chunk)) = chunks.get_mut(*entity) else {
+            continue;
+        };
+        let origin = chunk_origin(*coord);
+        let sample = |local| {
+            let pos = origin + local;
+            lvl.get(pos).filter(|_| cutaway.0.is_none_or(|top| pos.y <= top))
+        };
+        let opaque = |id| mats.is_opaque(id);
+        let passes = [
+            (ChunkLayer::OpaqueMesh, greedy_mesh(sample, opaque_face(opaque), |id| mats.color(id), lvl.tile_scale), &chunk_mat.opaque),
+            (ChunkLayer::TranslucentMesh, greedy_mesh(sample, translucent_face(opaque), |id| mats.color(id), lvl.tile_scale), &chunk_mat.translucent),
+        ];
+        for (layer, data, material) in passes {
+            let bundle = (!data.is_empty()).then(|| PbrBundle {
+                mesh: meshes.add(data.into_mesh()),
+                material: material.clone(),
                 ..default()
-            },
-            mat: Material {
-                id: mat.id,
-            },
-            body: RigidBody::Fixed,
-            colllider: Collider::cuboid(tile_scale, tile_scale, tile_scale),
+            });
+            if layer == ChunkLayer::OpaqueMesh {
+                chunk.set_layer(&mut commands, *entity, layer, bundle.map(|b| (b, Opaque)));
+            } else {
+                chunk.set_layer(&mut commands, *entity, layer, bundle);
+            }
         }
     }
 }
 
+/// Whether moving the cutaway from `old` to `new` changes any tile a chunk's mesh samples, counting the
+/// layer on either side that its faces are culled against. `None` is no cut at all.
+fn cut_crosses(coord: IVec3, old: Option<i32>, new: Option<i32>) -> bool {
+    // Tiles in `lo + 1..=hi` appear or disappear; with no upper bound, everything above `lo` does.
+    let (lo, hi) = match (old, new) {
+        (Some(a), Some(b)) if a != b => (a.min(b), Some(a.max(b))),
+        (Some(a), None) | (None, Some(a)) => (a, None),
+        _ => return false,
+    };
+    let bottom = chunk_origin(coord).y - 1;
+    let top = bottom + CHUNK_SIZE + 1;
+    top > lo && hi.is_none_or(|hi| bottom <= hi)
+}
+
+fn build_chunk_colliders(
+    mut commands: Commands,
+    mut changed: EventReader<ChunkChanged>,
+    mut chunks: Query<&mut TileChunk>,
+    lvl: Res<Level>,
+    mats: Res<MaterialTypes>,
+) {
+    for ChunkChanged { coord, entity } in changed.iter() {
+        let (Some(tiles), Ok(mut chunk)) = (lvl.chunks.get(coord), chunks.get_mut(*entity)) else {
+            continue;
+        };
+        let collider = |include: fn(&MaterialType) -> bool| {
+            chunk_collider(&merge_boxes(tiles, |id| mats.get(id).is_some_and(include)), lvl.tile_scale)
+        };
+        let solid = chunk_collider(&merge_boxes(tiles, |id| mats.is_solid(id)), lvl.tile_scale);
+        chunk.set_layer(&mut commands, *entity, ChunkLayer::Solid, solid.map(|c| (c, Solid, TransformBundle::default())));
+        // Non-solid volumes are sensors that report overlaps with characters instead of blocking them.
+        let sensor = |c: Collider| (c, Sensor, ActiveCollisionTypes::default() | ActiveCollisionTypes::KINEMATIC_STATIC, TransformBundle::default());
+        let liquid = collider(|m| m.liquid && !m.solid);
+        chunk.set_layer(&mut commands, *entity, ChunkLayer::Liquid, liquid.map(|c| (sensor(c), Liquid)));
+        let climbable = collider(|m| m.climbable && !m.solid);
+        chunk.set_layer(&mut commands, *entity, ChunkLayer::Climbable, climbable.map(|c| (sensor(c), Climbable)));
+        let hazardous = collider(|m| m.hazardous && !m.solid);
+        chunk.set_layer(&mut commands, *entity, ChunkLayer::Hazardous, hazardous.map(|c| (sensor(c), Hazardous)));
+    }
+}
+
+/// A tile command that changed the level, sent after the change is made.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TileEdit {
+    Spawn { pos: IVec3, mat: usize },
+    Destroy { pos: IVec3 },
+    DestroyRect { min: IVec3, max: IVec3 },
+}
+
+impl TileEdit {
+    /// Spawns the command that makes this edit.
+    pub fn spawn_command(self, commands: &mut Commands) -> Entity {
+        match self {
+            Self::Spawn { pos, mat } => commands.spawn(CmdSpawnTile { pos, mat }).id(),
+            Self::Destroy { pos } => commands.spawn(CmdDestroyTile { pos }).id(),
+            Self::DestroyRect { min, max } => commands.spawn(CmdDestroyTileRect { min, max }).id(),
+        }
+    }
+}
+
+/// Tile commands of every kind that haven't been applied yet.
+#[derive(SystemParam)]
+pub struct QueuedTileCommands<'w, 's> {
+    spawns: Query<'w, 's, Entity, With<CmdSpawnTile>>,
+    destroys: Query<'w, 's, Entity, With<CmdDestroyTile>>,
+    rects: Query<'w, 's, Entity, With<CmdDestroyTileRect>>,
+}
+
+impl QueuedTileCommands<'_, '_> {
+    pub fn iter(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.spawns.iter().chain(&self.destroys).chain(&self.rects)
+    }
+}
+
+/// Tile commands wait while the level is being replaced, so they land on the new level rather than the old.
+fn level_settled(
+    state: Res<State<LvlState>>,
+    inits: Query<(), With<CmdLvlInit>>,
+    loads: Query<(), With<CmdLvlLoad>>,
+    snapshots: Query<(), With<CmdLvlSnapshot>>,
+) -> bool {
+    state.0 == LvlState::Ready && inits.is_empty() && loads.is_empty() && snapshots.is_empty()
+}
+
 #[derive(Component)]
 pub struct CmdSpawnTile {
-    pub pos: [usize; 3],
+    pub pos: IVec3,
     pub mat: usize,
 }
 
-fn spawn_tile(mut commands: Commands, spawns: Query<(Entity, &CmdSpawnTile)>, mut lvl: ResMut<Level>, mats: Res<MaterialTypes>) {
+fn spawn_tile(
+    mut commands: Commands,
+    spawns: Query<(Entity, &CmdSpawnTile)>,
+    mut lvl: ResMut<Level>,
+    mats: Res<MaterialTypes>,
+    mut edits: EventWriter<TileEdit>,
+) {
     for (ent, spawn) in &spawns {
-        if lvl.tiles[spawn.pos].is_none() {
-            let material_type = &mats.map[&spawn.mat];
-            lvl.tiles[spawn.pos] = Some(commands.spawn(TileBundle::new(spawn.pos, material_type, lvl.tile_scale)).id());
+        if lvl.get(spawn.pos).is_none() && mats.get(spawn.mat).is_some() {
+            lvl.set(spawn.pos, Some(spawn.mat));
+            edits.send(TileEdit::Spawn { pos: spawn.pos, mat: spawn.mat });
         }
         commands.entity(ent).despawn();
     }
@@ -156,14 +462,13 @@ fn spawn_tile(mut commands: Commands, spawns: Query<(Entity, &CmdSpawnTile)>, mu
 
 #[derive(Component)]
 pub struct CmdDestroyTile {
-    pub pos: [usize; 3],
+    pub pos: IVec3,
 }
 
-fn destroy_tile(mut commands: Commands, destroys: Query<(Entity, &CmdDestroyTile)>, mut lvl: ResMut<Level>) {
+fn destroy_tile(mut commands: Commands, destroys: Query<(Entity, &CmdDestroyTile)>, mut lvl: ResMut<Level>, mut edits: EventWriter<TileEdit>) {
     for (ent, destroy) in &destroys {
-        if let Some(tile_entity) = lvl.tiles[destroy.pos] {
-            commands.entity(tile_entity).despawn();
-            lvl.tiles[destroy.pos] = None;
+        if lvl.set(destroy.pos, None).is_some() {
+            edits.send(TileEdit::Destroy { pos: destroy.pos });
         }
         commands.entity(ent).despawn();
     }
@@ -171,58 +476,397 @@ fn destroy_tile(mut commands: Commands, destroys: Query<(Entity, &CmdDestroyTile
 
 #[derive(Component)]
 pub struct CmdDestroyTileRect {
-    pub min: [usize; 3],
-    pub max: [usize; 3],
+    pub min: IVec3,
+    pub max: IVec3,
 }
 
-fn destroy_tile_rect(mut commands: Commands, destroys: Query<(Entity, &CmdDestroyTileRect)>, mut lvl: ResMut<Level>) {
+fn destroy_tile_rect(
+    mut commands: Commands,
+    destroys: Query<(Entity, &CmdDestroyTileRect)>,
+    mut lvl: ResMut<Level>,
+    mut edits: EventWriter<TileEdit>,
+) {
     for (ent, destroy) in &destroys {
-        for x in destroy.min[0]..=destroy.max[0] {
-            for y in destroy.min[1]..=destroy.max[1] {
-                for z in destroy.min[2]..=destroy.max[2] {
-                    let pos = [x,y,z];
-                    if let Some(tile_entity) = lvl.tiles[pos] {
-                        commands.entity(tile_entity).despawn();
-                        lvl.tiles[pos] = None;
-                    }
+        commands.entity(ent).despawn();
+        // Only the part of the rect holding tiles is walked, however big the rect.
+        let Some((lo, hi)) = lvl.bounds() else {
+            continue;
+        };
+        let (min, max) = (destroy.min.max(lo), destroy.max.min(hi));
+        let mut removed = false;
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    removed |= lvl.set(IVec3::new(x, y, z), None).is_some();
                 }
             }
         }
-        commands.entity(ent).despawn();
+        if removed {
+            edits.send(TileEdit::DestroyRect { min, max });
+        }
     }
 }
 
-#[derive(Component)]
+#[derive(Component, Default)]
 pub struct CmdLvlInit {
     pub seed: u64,
+    pub generator: GeneratorConfig,
+}
+
+/// How far the level is through being built, for loading screens and progress bars.
+#[derive(Resource, Clone, Default)]
+pub struct LvlProgress {
+    pub stage: String,
+    /// 0.0 when a level is requested, 1.0 once every tile is in place.
+    pub fraction: f32,
 }
 
-pub fn gen_dungeon_init(mut commands: Commands, inits: Query<(Entity, &CmdLvlInit)>, mut lvl: ResMut<Level>, mats: Res<MaterialTypes>) {
+/// Share of `LvlProgress` spent in the generator; the rest is placing its tiles.
+const GENERATE_SHARE: f32 = 0.8;
+/// Tiles copied into the level per frame once a generator finishes.
+const TILES_PER_FRAME: usize = 16 * 1024;
+
+/// The generation in flight, if any.
+#[derive(Resource, Default)]
+struct LvlGeneration {
+    task: Option<Task<TileGrid>>,
+    progress: GenProgress,
+    grid: Option<TileGrid>,
+    placed: usize,
+}
+
+/// Starts generating the level on the async compute pool; `poll_generation` picks the result up.
+fn gen_dungeon_init(
+    mut commands: Commands,
+    inits: Query<(Entity, &CmdLvlInit)>,
+    mut lvl: ResMut<Level>,
+    mats: Res<MaterialTypes>,
+    mut gen: ResMut<LvlGeneration>,
+    mut progress: ResMut<LvlProgress>,
+) {
     for (ent, init) in &inits {
-        let mut noise = FastNoise::seeded(init.seed);
-        noise.set_noise_type(rltk::NoiseType::Perlin);
-        noise.set_frequency(0.1);
-        for x in 0..lvl.size[0] {
-            for y in 0..lvl.size[1] {
-                for z in 0..lvl.size[2] {
-                    let pos = [x,y,z];
-                    let stone = mats.get_mat("Stone").unwrap();
-                    let dirt = mats.get_mat("Dirt").unwrap();
-                    let wood = mats.get_mat("Wood").unwrap();
-                    let val = noise.get_noise3d(x as f32, y as f32, z as f32) / 2.0 + 0.5;
-                    let mut t = stone;
-                    if val < 0.05 {
-                        t = wood;
-                    } else if val < 0.25 {
-                        t = dirt;
-                    }
-                    lvl.tiles[pos] = Some(commands.spawn(
-                        TileBundle::new(pos, t, lvl.tile_scale)
-                    ).id());
-                }
+        commands.entity(ent).despawn();
+        let (Some(wall), Some(floor), Some(cave)) = (mats.get_mat("Stone"), mats.get_mat("Wood"), mats.get_mat("Dirt")) else {
+            println!("Dungeon generation needs Stone, Wood and Dirt materials.");
+            continue;
+        };
+        let palette = DungeonPalette { wall: wall.id, floor: floor.id, cave: cave.id };
+        let size = IVec3::new(lvl.size[0] as i32, lvl.size[1] as i32, lvl.size[2] as i32);
+        if let Err(e) = init.generator.validate(size) {
+            println!("Can't generate with {:?}: {e}", init.generator);
+            continue;
+        }
+        let generator = init.generator.build();
+        let seed = init.seed;
+        let task_progress = GenProgress::default();
+        *gen = LvlGeneration {
+            progress: task_progress.clone(),
+            ..default()
+        };
+        gen.task = Some(AsyncComputeTaskPool::get().spawn(async move {
+            generator.generate(seed, size, palette, &task_progress)
+        }));
+        lvl.clear();
+        *progress = LvlProgress {
+            stage: "Generating".to_owned(),
+            fraction: 0.0,
+        };
+        println!("Generating dungeon from seed {} with {:?}...", init.seed, init.generator);
+    }
+}
+
+/// Follows the running generator, then copies its tiles into the level a slice per frame so
+/// meshing is spread out too. Moves to `Ready` once every tile is placed.
+fn poll_generation(
+    mut gen: ResMut<LvlGeneration>,
+    mut lvl: ResMut<Level>,
+    mut progress: ResMut<LvlProgress>,
+    mut next_state: ResMut<NextState<LvlState>>,
+) {
+    if let Some(task) = gen.task.take() {
+        if !task.is_finished() {
+            let (stage, fraction) = gen.progress.get();
+            progress.stage = stage.to_owned();
+            progress.fraction = fraction * GENERATE_SHARE;
+            gen.task = Some(task);
+            return;
+        }
+        gen.grid = Some(futures_lite::future::block_on(task));
+        gen.placed = 0;
+    }
+    let Some(grid) = &gen.grid else {
+        return;
+    };
+    let total = grid.volume();
+    for (pos, tile) in grid.iter_from(gen.placed).take(TILES_PER_FRAME) {
+        lvl.set(pos, tile);
+    }
+    let placed = (gen.placed + TILES_PER_FRAME).min(total);
+    progress.stage = "Placing tiles".to_owned();
+    progress.fraction = GENERATE_SHARE + (1.0 - GENERATE_SHARE) * placed as f32 / total.max(1) as f32;
+    gen.placed = placed;
+    if placed == total {
+        if let Some(grid) = gen.grid.take() {
+            lvl.spawns = grid.spawns;
+        }
+        progress.stage = "Done".to_owned();
+        next_state.set(LvlState::Ready);
+        println!("Dungeon generated.");
+    }
+}
+
+/// Writes the level to a level file at `path`.
+#[derive(Component)]
+pub struct CmdLvlSave {
+    pub path: PathBuf,
+}
+
+fn save_level(mut commands: Commands, saves: Query<(Entity, &CmdLvlSave)>, lvl: Res<Level>, mats: Res<MaterialTypes>) {
+    for (ent, save) in &saves {
+        let result = LevelSnapshot::capture(&lvl, &mats)
+            .and_then(|snapshot| snapshot.encode())
+            .and_then(|bytes| Ok(std::fs::write(&save.path, bytes)?));
+        match result {
+            Ok(()) => println!("Level saved to {}.", save.path.display()),
+            Err(e) => println!("Failed to save level to {}: {e}", save.path.display()),
+        }
+        commands.entity(ent).despawn();
+    }
+}
+
+/// Replaces the level with the contents of the level file at `path`.
+#[derive(Component)]
+pub struct CmdLvlLoad {
+    pub path: PathBuf,
+}
+
+fn load_level(
+    mut commands: Commands,
+    loads: Query<(Entity, &CmdLvlLoad)>,
+    mut lvl: ResMut<Level>,
+    mats: Res<MaterialTypes>,
+    mut gen: ResMut<LvlGeneration>,
+    mut progress: ResMut<LvlProgress>,
+    mut next_state: ResMut<NextState<LvlState>>,
+) {
+    for (ent, load) in &loads {
+        // A loaded level replaces whatever was being generated.
+        *gen = LvlGeneration::default();
+        let result = std::fs::read(&load.path)
+            .map_err(Into::into)
+            .and_then(|bytes| LevelSnapshot::decode(&bytes))
+            .and_then(|snapshot| snapshot.apply(&mut lvl, &mats));
+        match result {
+            Ok(()) => {
+                println!("Level loaded from {}.", load.path.display());
+                *progress = LvlProgress {
+                    stage: "Done".to_owned(),
+                    fraction: 1.0,
+                };
+                next_state.set(LvlState::Ready);
             }
+            Err(e) => println!("Failed to load level from {}: {e}", load.path.display()),
         }
         commands.entity(ent).despawn();
-        println!("Dungeon generated default materials.")
     }
-}
\ No newline at end of file
+}
+
+/// Replaces the level with a snapshot received from elsewhere, such as a server.
+/// The snapshot waits until materials have loaded, since it names its tiles' materials.
+#[derive(Component)]
+pub struct CmdLvlSnapshot {
+    pub snapshot: LevelSnapshot,
+}
+
+fn load_snapshot(
+    mut commands: Commands,
+    loads: Query<(Entity, &CmdLvlSnapshot)>,
+    mut lvl: ResMut<Level>,
+    mats: Res<MaterialTypes>,
+    mut gen: ResMut<LvlGeneration>,
+    mut progress: ResMut<LvlProgress>,
+    mut next_state: ResMut<NextState<LvlState>>,
+) {
+    if mats.is_empty() {
+        return;
+    }
+    for (ent, load) in &loads {
+        *gen = LvlGeneration::default();
+        match load.snapshot.apply(&mut lvl, &mats) {
+            Ok(()) => {
+                *progress = LvlProgress {
+                    stage: "Done".to_owned(),
+                    fraction: 1.0,
+                };
+                next_state.set(LvlState::Ready);
+            }
+            Err(e) => println!("Failed to apply level snapshot: {e}"),
+        }
+        commands.entity(ent).despawn();
+    }
+}
+
+/// Leaves `Ready` when another level is requested; the request itself is handled once back in `Generate`.
+fn request_regenerate(
+    inits: Query<(), With<CmdLvlInit>>,
+    loads: Query<(), With<CmdLvlLoad>>,
+    snapshots: Query<(), With<CmdLvlSnapshot>>,
+    mut next_state: ResMut<NextState<LvlState>>,
+) {
+    if !inits.is_empty() || !loads.is_empty() || !snapshots.is_empty() {
+        next_state.set(LvlState::Clean);
+    }
+}
+
+/// Despawns every chunk entity and empties the level, then goes back to `Generate`.
+fn clean_level(
+    mut commands: Commands,
+    mut lvl: ResMut<Level>,
+    mut gen: ResMut<LvlGeneration>,
+    mut next_state: ResMut<NextState<LvlState>>,
+) {
+    *gen = LvlGeneration::default();
+    for (_, ent) in lvl.chunk_entities.drain() {
+        commands.entity(ent).despawn_recursive();
+    }
+    lvl.clear();
+    lvl.dirty.clear();
+    println!("Level cleaned.");
+    next_state.set(LvlState::Generate);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{dungeon_materials, stone};
+
+    #[test]
+    fn new_chunks_get_a_mesh_and_a_collider_in_the_same_frame() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(AssetPlugin::default())
+            .add_asset::<Mesh>()
+            .add_asset::<StandardMaterial>()
+            .insert_resource(stone())
+            .add_plugin(LvlPlugin)
+            .add_plugin(LvlRenderPlugin);
+        app.world.resource_mut::<Level>().set(IVec3::new(20, 3, 40), Some(0));
+        app.update();
+
+        let lvl = app.world.resource::<Level>();
+        let chunk = lvl.chunk_entities[&chunk_coord(IVec3::new(20, 3, 40))];
+        let layers = &app.world.get::<TileChunk>(chunk).unwrap().layers;
+        let mesh = layers[&ChunkLayer::OpaqueMesh];
+        let solid = layers[&ChunkLayer::Solid];
+        assert!(app.world.get::<Handle<Mesh>>(mesh).is_some());
+        assert!(app.world.get::<Collider>(solid).is_some());
+    }
+
+    fn ready_level() -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(stone())
+            .add_plugin(LvlPlugin);
+        app.world.resource_mut::<NextState<LvlState>>().set(LvlState::Ready);
+        app.update();
+        app
+    }
+
+
+    #[test]
+    fn tile_commands_in_one_tick_apply_spawns_before_destroys() {
+        let mut app = ready_level();
+        let pos = IVec3::new(1, 2, 3);
+        app.world.spawn(CmdDestroyTile { pos });
+        app.world.spawn(CmdSpawnTile { pos, mat: 0 });
+        app.world.spawn(CmdSpawnTile { pos: IVec3::X, mat: 0 });
+        app.world.run_schedule(CoreSchedule::FixedUpdate);
+
+        let lvl = app.world.resource::<Level>();
+        assert_eq!(lvl.get(pos), None);
+        assert_eq!(lvl.get(IVec3::X), Some(0));
+        let edits: Vec<TileEdit> = app.world.resource_mut::<Events<TileEdit>>().drain().collect();
+        assert_eq!(edits.len(), 3);
+        assert_eq!(edits.last(), Some(&TileEdit::Destroy { pos }));
+    }
+
+    #[test]
+    fn destroyed_rects_only_cover_the_level_and_skip_empty_space() {
+        let mut app = ready_level();
+        app.world.resource_mut::<Level>().set(IVec3::new(2, 1, 2), Some(0));
+        app.world.resource_mut::<Level>().set(IVec3::new(5, 1, 3), Some(0));
+        app.world.spawn(CmdDestroyTileRect { min: IVec3::new(3, -1_000_000, 0), max: IVec3::splat(1_000_000) });
+        app.world.spawn(CmdDestroyTileRect { min: IVec3::splat(i32::MIN), max: IVec3::new(2, 0, 2) });
+        app.world.run_schedule(CoreSchedule::FixedUpdate);
+
+        let lvl = app.world.resource::<Level>();
+        assert_eq!(lvl.get(IVec3::new(5, 1, 3)), None);
+        assert_eq!(lvl.get(IVec3::new(2, 1, 2)), Some(0));
+        let edits: Vec<TileEdit> = app.world.resource_mut::<Events<TileEdit>>().drain().collect();
+        assert_eq!(edits, [TileEdit::DestroyRect { min: IVec3::new(3, 1, 2), max: IVec3::new(5, 1, 3) }]);
+    }
+
+    #[test]
+    fn tile_commands_wait_for_a_level_being_replaced() {
+        let mut app = ready_level();
+        app.world.spawn(CmdLvlInit::default());
+        let cmd = app.world.spawn(CmdSpawnTile { pos: IVec3::ONE, mat: 0 }).id();
+        app.world.run_schedule(CoreSchedule::FixedUpdate);
+
+        assert_eq!(app.world.resource::<Level>().get(IVec3::ONE), None);
+        assert!(app.world.get_entity(cmd).is_some());
+    }
+
+    #[test]
+    fn cutaway_moves_only_touch_chunks_across_the_cut() {
+        let chunks = |old, new| (0..4).filter(|y| cut_crosses(IVec3::new(0, *y, 0), old, new)).collect::<Vec<i32>>();
+        assert_eq!(chunks(Some(20), Some(21)), [1]);
+        // The chunk below still samples the first layer of the one above for culling.
+        assert_eq!(chunks(Some(15), Some(16)), [0, 1]);
+        assert_eq!(chunks(Some(31), Some(20)), [1, 2]);
+        assert_eq!(chunks(None, Some(40)), [2, 3]);
+        assert_eq!(chunks(Some(40), None), [2, 3]);
+        assert!(chunks(Some(5), Some(5)).is_empty());
+        assert!(chunks(None, None).is_empty());
+    }
+
+    /// A snapshot of a level holding one stone tile at `pos`.
+    fn one_tile(pos: IVec3) -> CmdLvlSnapshot {
+        let mut lvl = Level::default();
+        lvl.set(pos, Some(0));
+        CmdLvlSnapshot { snapshot: LevelSnapshot::capture(&lvl, &stone()).unwrap() }
+    }
+
+    #[test]
+    fn snapshots_replace_a_generation_started_in_the_same_frame() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(dungeon_materials())
+            .add_plugin(LvlPlugin);
+        app.world.spawn(CmdLvlInit::default());
+        app.world.spawn(one_tile(IVec3::ONE));
+        for _ in 0..3 {
+            app.update();
+        }
+
+        assert_eq!(app.world.resource::<State<LvlState>>().0, LvlState::Ready);
+        let lvl = app.world.resource::<Level>();
+        assert_eq!(lvl.iter().collect::<Vec<_>>(), [(IVec3::ONE, 0)]);
+    }
+
+    #[test]
+    fn snapshots_wait_for_materials() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .init_resource::<MaterialTypes>()
+            .add_plugin(LvlPlugin);
+        let cmd = app.world.spawn(one_tile(IVec3::ONE)).id();
+        app.update();
+        assert!(app.world.get_entity(cmd).is_some());
+
+        app.insert_resource(stone());
+        app.update();
+        assert!(app.world.get_entity(cmd).is_none());
+        assert_eq!(app.world.resource::<Level>().get(IVec3::ONE), Some(0));
+    }
+}
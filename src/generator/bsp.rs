@@ -0,0 +1,126 @@
+use bevy::prelude::*;
+use rltk::{FastNoise, RandomNumberGenerator, Rect};
+
+use crate::dungeon::LevelSpawn;
+
+use super::{carve_area, carve_corridor, carve_stairs, storey_count, DungeonGenerator, DungeonPalette, GenProgress, TileGrid, FLOOR_HEIGHT, HEADROOM};
+
+/// Carves a multi-storey dungeon out of solid rock. Each storey gets BSP rooms joined by corridors,
+/// optional noise caves, and a staircase up to the storey above.
+pub struct BspRooms {
+    pub min_leaf: i32,
+    pub min_room: i32,
+    pub caves: bool,
+}
+
+impl DungeonGenerator for BspRooms {
+    fn generate(&self, seed: u64, size: IVec3, palette: DungeonPalette, progress: &GenProgress) -> TileGrid {
+        let mut rng = RandomNumberGenerator::seeded(seed);
+        let mut grid = TileGrid::new(size, Some(palette.wall));
+        let bounds = Rect::with_exact(1, 1, size.x - 2, size.z - 2);
+
+        let mut storeys = Vec::new();
+        let count = storey_count(size.y);
+        for floor in 0..count {
+            progress.report("Carving rooms", 0.9 * floor as f32 / count as f32);
+            let base = floor * FLOOR_HEIGHT;
+            let mut leaves = Vec::new();
+            self.split(&mut rng, bounds, &mut leaves);
+            let rooms: Vec<Rect> = leaves.iter()
+                .filter(|leaf| leaf.width() > self.min_room + 1 && leaf.height() > self.min_room + 1)
+                .map(|leaf| self.place_room(&mut rng, *leaf))
+                .collect();
+            for pair in rooms.windows(2) {
+                carve_corridor(&mut grid, &mut rng, pair[0].center(), pair[1].center(), base, palette.cave);
+            }
+            if self.caves {
+                carve_caves(&mut grid, seed.wrapping_add(floor as u64), base, palette.cave);
+            }
+            for room in &rooms {
+                carve_area(&mut grid, *room, base, HEADROOM, palette.floor);
+            }
+            storeys.push(rooms);
+        }
+
+        if let Some(first) = storeys.first().and_then(|rooms| rooms.first()) {
+            let c = first.center();
+            grid.spawns.push(LevelSpawn::player(IVec3::new(c.x, 1, c.y)));
+        }
+        progress.report("Linking storeys", 0.9);
+        for (floor, pair) in storeys.windows(2).enumerate() {
+            link_storeys(&mut grid, &mut rng, &pair[0], &pair[1], floor as i32 * FLOOR_HEIGHT, palette);
+        }
+        grid
+    }
+}
+
+impl BspRooms {
+    fn split(&self, rng: &mut RandomNumberGenerator, rect: Rect, leaves: &mut Vec<Rect>) {
+        let w = rect.width();
+        let h = rect.height();
+        let min = self.min_leaf;
+        let split_x = if w >= min * 2 && h >= min * 2 {
+            rng.range(0, 2) == 0
+        } else if w >= min * 2 {
+            true
+        } else if h >= min * 2 {
+            false
+        } else {
+            leaves.push(rect);
+            return;
+        };
+        if split_x {
+            let at = rect.x1 + rng.range(min, w - min + 1);
+            self.split(rng, Rect::with_exact(rect.x1, rect.y1, at, rect.y2), leaves);
+            self.split(rng, Rect::with_exact(at, rect.y1, rect.x2, rect.y2), leaves);
+        } else {
+            let at = rect.y1 + rng.range(min, h - min + 1);
+            self.split(rng, Rect::with_exact(rect.x1, rect.y1, rect.x2, at), leaves);
+            self.split(rng, Rect::with_exact(rect.x1, at, rect.x2, rect.y2), leaves);
+        }
+    }
+
+    /// Picks a room inside a leaf, keeping a one tile wall to its neighbours.
+    fn place_room(&self, rng: &mut RandomNumberGenerator, leaf: Rect) -> Rect {
+        let w = rng.range(self.min_room, leaf.width() - 1);
+        let h = rng.range(self.min_room, leaf.height() - 1);
+        let x = leaf.x1 + 1 + rng.range(0, leaf.width() - w - 1);
+        let z = leaf.y1 + 1 + rng.range(0, leaf.height() - h - 1);
+        Rect::with_size(x, z, w, h)
+    }
+}
+
+/// Hollows out organic pockets wherever the storey's noise field peaks.
+fn carve_caves(grid: &mut TileGrid, seed: u64, base: i32, floor_mat: usize) {
+    let mut noise = FastNoise::seeded(seed);
+    noise.set_noise_type(rltk::NoiseType::Perlin);
+    noise.set_frequency(0.08);
+    for z in 1..grid.size.z - 1 {
+        for x in 1..grid.size.x - 1 {
+            let val = noise.get_noise(x as f32, z as f32) / 2.0 + 0.5;
+            if val > 0.62 {
+                let height = (3 + ((val - 0.62) * 20.0) as i32).min(HEADROOM);
+                carve_area(grid, Rect::with_exact(x, z, x, z), base, height, floor_mat);
+            }
+        }
+    }
+}
+
+/// Puts a staircase in a wide enough room on the lower storey and joins its landing to the nearest room above.
+fn link_storeys(grid: &mut TileGrid, rng: &mut RandomNumberGenerator, lower: &[Rect], upper: &[Rect], base: i32, palette: DungeonPalette) {
+    let candidates: Vec<&Rect> = lower.iter()
+        .filter(|r| r.width() > FLOOR_HEIGHT + 1 && r.height() >= 2 && r.x1 + FLOOR_HEIGHT + 5 < grid.size.x - 1)
+        .collect();
+    if candidates.is_empty() {
+        return;
+    }
+    let room = candidates[rng.range(0, candidates.len() as i32) as usize];
+    let site = rltk::Point::new(room.x1, room.y1 + rng.range(0, room.height() - 1));
+    let landing = carve_stairs(grid, site, base, palette);
+    if let Some(target) = upper.iter().min_by_key(|r| {
+        let c = r.center();
+        (c.x - landing.x).abs() + (c.y - landing.y).abs()
+    }) {
+        carve_corridor(grid, rng, landing, target.center(), base + FLOOR_HEIGHT, palette.cave);
+    }
+}
@@ -0,0 +1,97 @@
+use bevy::prelude::*;
+use rltk::RandomNumberGenerator;
+
+use super::{carve_plans, storey_count, DungeonGenerator, DungeonPalette, FloorPlan, GenProgress, TileGrid};
+
+/// Seeds each storey with random rock and smooths it into caverns with a cellular automaton.
+/// The automaton runs on cells of `scale` tiles so passages stay wide enough to walk through,
+/// and only the largest connected cavern is kept.
+pub struct CellularCaves {
+    pub fill_chance: f32,
+    pub iterations: u32,
+    pub scale: i32,
+}
+
+impl DungeonGenerator for CellularCaves {
+    fn generate(&self, seed: u64, size: IVec3, palette: DungeonPalette, progress: &GenProgress) -> TileGrid {
+        let mut rng = RandomNumberGenerator::seeded(seed);
+        let scale = self.scale.max(1);
+        let (w, d) = (size.x / scale, size.z / scale);
+        let count = storey_count(size.y);
+        let plans: Vec<FloorPlan> = (0..count).map(|floor| {
+            progress.report("Planning storeys", 0.5 * floor as f32 / count as f32);
+            let mut cells = FloorPlan::new(w, d);
+            for z in 0..d {
+                for x in 0..w {
+                    cells.set(x, z, rng.range(0.0, 1.0) >= self.fill_chance);
+                }
+            }
+            for _ in 0..self.iterations {
+                cells = smooth(&cells);
+            }
+            keep_largest_region(&mut cells);
+            upscale(&cells, scale, size)
+        }).collect();
+
+        let mut grid = TileGrid::new(size, Some(palette.wall));
+        carve_plans(&mut grid, &mut rng, &plans, palette, progress);
+        grid
+    }
+}
+
+/// A cell becomes rock when five or more of its 3x3 neighbourhood is rock.
+fn smooth(cells: &FloorPlan) -> FloorPlan {
+    let mut next = FloorPlan::new(cells.width, cells.depth);
+    for z in 0..cells.depth {
+        for x in 0..cells.width {
+            let walls = (-1..=1).flat_map(|dz| (-1..=1).map(move |dx| (dx, dz)))
+                .filter(|(dx, dz)| !cells.is_open(x + dx, z + dz))
+                .count();
+            next.set(x, z, walls < 5);
+        }
+    }
+    next
+}
+
+fn keep_largest_region(cells: &mut FloorPlan) {
+    let mut region = vec![usize::MAX; (cells.width * cells.depth) as usize];
+    let mut sizes = Vec::new();
+    for start in cells.open_cells().collect::<Vec<_>>() {
+        if region[(start.x + start.y * cells.width) as usize] != usize::MAX {
+            continue;
+        }
+        let id = sizes.len();
+        let mut count = 0;
+        let mut stack = vec![start];
+        region[(start.x + start.y * cells.width) as usize] = id;
+        while let Some(p) = stack.pop() {
+            count += 1;
+            for (dx, dz) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                let (x, z) = (p.x + dx, p.y + dz);
+                if cells.is_open(x, z) && region[(x + z * cells.width) as usize] == usize::MAX {
+                    region[(x + z * cells.width) as usize] = id;
+                    stack.push(rltk::Point::new(x, z));
+                }
+            }
+        }
+        sizes.push(count);
+    }
+    let Some(largest) = (0..sizes.len()).max_by_key(|i| sizes[*i]) else {
+        return;
+    };
+    for p in cells.open_cells().collect::<Vec<_>>() {
+        if region[(p.x + p.y * cells.width) as usize] != largest {
+            cells.set(p.x, p.y, false);
+        }
+    }
+}
+
+fn upscale(cells: &FloorPlan, scale: i32, size: IVec3) -> FloorPlan {
+    let mut plan = FloorPlan::new(size.x, size.z);
+    for z in 0..size.z {
+        for x in 0..size.x {
+            plan.set(x, z, cells.is_open(x / scale, z / scale));
+        }
+    }
+    plan
+}
@@ -0,0 +1,47 @@
+use bevy::prelude::*;
+use rltk::RandomNumberGenerator;
+
+use super::{carve_plans, storey_count, DungeonGenerator, DungeonPalette, FloorPlan, GenProgress, TileGrid, CORRIDOR_WIDTH};
+
+/// Sends `walkers` random walkers of `steps` steps across each storey. The first starts in the centre,
+/// later ones start from a random spot that is already open, so the result is always connected.
+pub struct DrunkardsWalk {
+    pub walkers: u32,
+    pub steps: u32,
+}
+
+impl DungeonGenerator for DrunkardsWalk {
+    fn generate(&self, seed: u64, size: IVec3, palette: DungeonPalette, progress: &GenProgress) -> TileGrid {
+        let mut rng = RandomNumberGenerator::seeded(seed);
+        let radius = CORRIDOR_WIDTH / 2;
+        let count = storey_count(size.y);
+        let plans: Vec<FloorPlan> = (0..count).map(|floor| {
+            progress.report("Planning storeys", 0.5 * floor as f32 / count as f32);
+            let mut plan = FloorPlan::new(size.x, size.z);
+            let (mut x, mut z) = (size.x / 2, size.z / 2);
+            for walker in 0..self.walkers {
+                let open: Vec<_> = plan.open_cells().collect();
+                if walker > 0 && !open.is_empty() {
+                    let start = open[rng.range(0, open.len())];
+                    (x, z) = (start.x, start.y);
+                }
+                for _ in 0..self.steps {
+                    plan.open_brush(x, z, radius);
+                    match rng.range(0, 4) {
+                        0 => x += 1,
+                        1 => x -= 1,
+                        2 => z += 1,
+                        _ => z -= 1,
+                    }
+                    x = x.clamp(radius + 1, size.x - radius - 2);
+                    z = z.clamp(radius + 1, size.z - radius - 2);
+                }
+            }
+            plan
+        }).collect();
+
+        let mut grid = TileGrid::new(size, Some(palette.wall));
+        carve_plans(&mut grid, &mut rng, &plans, palette, progress);
+        grid
+    }
+}
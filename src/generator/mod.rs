@@ -0,0 +1,362 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+use rltk::{RandomNumberGenerator, Rect, Point};
+use serde::{Deserialize, Serialize};
+
+use crate::dungeon::LevelSpawn;
+
+pub mod bsp;
+pub mod cellular;
+pub mod drunkard;
+pub mod noise;
+pub mod wfc;
+
+/// Tiles per storey: one floor slab, six tiles of headroom and one ceiling tile.
+pub const FLOOR_HEIGHT: i32 = 8;
+pub const HEADROOM: i32 = 6;
+pub const CORRIDOR_WIDTH: i32 = 3;
+const STAIR_LENGTH: i32 = FLOOR_HEIGHT;
+/// Walkers keep their brush and a solid border inside the level.
+const DRUNKARD_MIN_WIDTH: i32 = 2 * (CORRIDOR_WIDTH / 2) + 3;
+
+/// An algorithm that fills a region starting at the level origin with tiles.
+/// Implementations must be deterministic: the same seed, region and palette give the same grid.
+/// They run off the main thread and should report through `progress` as they go.
+pub trait DungeonGenerator: Send + Sync {
+    fn generate(&self, seed: u64, size: IVec3, palette: DungeonPalette, progress: &GenProgress) -> TileGrid;
+}
+
+/// The stage a running generator is in and how much of its work is done, shared with whoever waits on it.
+#[derive(Clone, Default)]
+pub struct GenProgress(Arc<Mutex<(&'static str, f32)>>);
+
+impl GenProgress {
+    pub fn report(&self, stage: &'static str, fraction: f32) {
+        if let Ok(mut p) = self.0.lock() {
+            *p = (stage, fraction.clamp(0.0, 1.0));
+        }
+    }
+
+    pub fn get(&self) -> (&'static str, f32) {
+        self.0.lock().map_or(("", 0.0), |p| *p)
+    }
+}
+
+/// Names a generator and its parameters, so levels can be described as data.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum GeneratorConfig {
+    /// Solid rock banded by 3D noise; the original test fill.
+    NoiseFill { frequency: f32 },
+    /// Rooms in binary space partitions joined by corridors, with noise caves.
+    Bsp { min_leaf: i32, min_room: i32, caves: bool },
+    /// Cellular automata smoothing of random noise into open caverns.
+    CellularCaves { fill_chance: f32, iterations: u32, scale: i32 },
+    /// Walkers wander from the centre and carve out wherever they step.
+    DrunkardsWalk { walkers: u32, steps: u32 },
+    /// Corridor pieces of `cell_size` tiles fitted together by their edges.
+    WaveFunctionCollapse { cell_size: i32 },
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self::Bsp { min_leaf: 12, min_room: 5, caves: true }
+    }
+}
+
+impl GeneratorConfig {
+    /// Names accepted by `named`, for command lines and menus.
+    pub const NAMES: [&'static str; 5] = ["noise", "bsp", "caves", "drunkard", "wfc"];
+
+    /// An algorithm with its usual parameters, by one of `NAMES`.
+    pub fn named(name: &str) -> Option<Self> {
+        Some(match name {
+            "noise" => Self::NoiseFill { frequency: 0.1 },
+            "bsp" => Self::default(),
+            "caves" => Self::CellularCaves { fill_chance: 0.45, iterations: 4, scale: 2 },
+            "drunkard" => Self::DrunkardsWalk { walkers: 6, steps: 400 },
+            "wfc" => Self::WaveFunctionCollapse { cell_size: 6 },
+            _ => return None,
+        })
+    }
+
+    /// Checks the parameters can generate a level of `size`, since generators trust them and would
+    /// otherwise panic or never finish.
+    pub fn validate(&self, size: IVec3) -> Result<(), String> {
+        match *self {
+            Self::NoiseFill { frequency } if !frequency.is_finite() => Err(format!("noise frequency {frequency} is not a number")),
+            Self::Bsp { min_leaf, .. } if min_leaf < 1 => Err(format!("BSP min_leaf {min_leaf} must be at least 1")),
+            Self::Bsp { min_room, .. } if min_room < 1 => Err(format!("BSP min_room {min_room} must be at least 1")),
+            Self::CellularCaves { fill_chance, .. } if !(0.0..=1.0).contains(&fill_chance) => {
+                Err(format!("cave fill_chance {fill_chance} must be between 0 and 1"))
+            }
+            Self::CellularCaves { scale, .. } if scale < 1 => Err(format!("cave scale {scale} must be at least 1")),
+            Self::DrunkardsWalk { .. } if size.x < DRUNKARD_MIN_WIDTH || size.z < DRUNKARD_MIN_WIDTH => {
+                Err(format!("drunkard's walk needs a level at least {DRUNKARD_MIN_WIDTH} tiles wide and deep"))
+            }
+            Self::WaveFunctionCollapse { cell_size } if cell_size < 1 => Err(format!("WFC cell_size {cell_size} must be at least 1")),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn DungeonGenerator> {
+        match self.clone() {
+            Self::NoiseFill { frequency } => Box::new(noise::NoiseFill { frequency }),
+            Self::Bsp { min_leaf, min_room, caves } => Box::new(bsp::BspRooms { min_leaf, min_room, caves }),
+            Self::CellularCaves { fill_chance, iterations, scale } => Box::new(cellular::CellularCaves { fill_chance, iterations, scale }),
+            Self::DrunkardsWalk { walkers, steps } => Box::new(drunkard::DrunkardsWalk { walkers, steps }),
+            Self::WaveFunctionCollapse { cell_size } => Box::new(wfc::WaveFunctionCollapse { cell_size }),
+        }
+    }
+}
+
+/// A dense block of generated tiles starting at the level origin.
+pub struct TileGrid {
+    pub size: IVec3,
+    tiles: Vec<Option<usize>>,
+    /// Points of interest found while generating, such as where players start.
+    pub spawns: Vec<LevelSpawn>,
+}
+
+impl TileGrid {
+    pub fn new(size: IVec3, fill: Option<usize>) -> Self {
+        Self {
+            size,
+            tiles: vec![fill; (size.x * size.y * size.z) as usize],
+            spawns: Vec::new(),
+        }
+    }
+
+    fn index(&self, pos: IVec3) -> Option<usize> {
+        if pos.cmplt(IVec3::ZERO).any() || pos.cmpge(self.size).any() {
+            return None;
+        }
+        Some((pos.x + pos.y * self.size.x + pos.z * self.size.x * self.size.y) as usize)
+    }
+
+    pub fn get(&self, pos: IVec3) -> Option<usize> {
+        self.index(pos).and_then(|i| self.tiles[i])
+    }
+
+    /// Sets the tile at `pos`; positions outside the grid are ignored.
+    pub fn set(&mut self, pos: IVec3, tile: Option<usize>) {
+        if let Some(i) = self.index(pos) {
+            self.tiles[i] = tile;
+        }
+    }
+
+    /// Sets every tile in the inclusive box `min..=max`.
+    pub fn fill(&mut self, min: IVec3, max: IVec3, tile: Option<usize>) {
+        for z in min.z..=max.z {
+            for y in min.y..=max.y {
+                for x in min.x..=max.x {
+                    self.set(IVec3::new(x, y, z), tile);
+                }
+            }
+        }
+    }
+
+    /// Number of tiles in the grid, empty or not.
+    pub fn volume(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (IVec3, Option<usize>)> + '_ {
+        self.iter_from(0)
+    }
+
+    /// Like `iter`, but starting at the `start`th tile without walking the ones before it.
+    pub fn iter_from(&self, start: usize) -> impl Iterator<Item = (IVec3, Option<usize>)> + '_ {
+        let size = self.size;
+        let start = start.min(self.tiles.len());
+        self.tiles[start..].iter().enumerate().map(move |(i, t)| {
+            let i = (start + i) as i32;
+            (IVec3::new(i % size.x, (i / size.x) % size.y, i / (size.x * size.y)), *t)
+        })
+    }
+}
+
+/// Material ids the generators build with.
+#[derive(Clone, Copy)]
+pub struct DungeonPalette {
+    pub wall: usize,
+    pub floor: usize,
+    pub cave: usize,
+}
+
+/// Number of storeys that fit in a region of height `size_y`.
+pub fn storey_count(size_y: i32) -> i32 {
+    (size_y / FLOOR_HEIGHT).max(1)
+}
+
+/// Which cells of one storey are open, for generators that work on a 2D plan.
+#[derive(Clone)]
+pub struct FloorPlan {
+    pub width: i32,
+    pub depth: i32,
+    open: Vec<bool>,
+}
+
+impl FloorPlan {
+    pub fn new(width: i32, depth: i32) -> Self {
+        Self {
+            width,
+            depth,
+            open: vec![false; (width * depth) as usize],
+        }
+    }
+
+    pub fn is_open(&self, x: i32, z: i32) -> bool {
+        x >= 0 && z >= 0 && x < self.width && z < self.depth && self.open[(x + z * self.width) as usize]
+    }
+
+    /// Opens or closes a cell, keeping a solid border around the plan.
+    pub fn set(&mut self, x: i32, z: i32, open: bool) {
+        if x >= 1 && z >= 1 && x < self.width - 1 && z < self.depth - 1 {
+            self.open[(x + z * self.width) as usize] = open;
+        }
+    }
+
+    /// Opens a square brush of `radius` tiles around the cell.
+    pub fn open_brush(&mut self, x: i32, z: i32, radius: i32) {
+        for dz in -radius..=radius {
+            for dx in -radius..=radius {
+                self.set(x + dx, z + dz, true);
+            }
+        }
+    }
+
+    pub fn open_cells(&self) -> impl Iterator<Item = Point> + '_ {
+        (0..self.depth).flat_map(move |z| (0..self.width).map(move |x| Point::new(x, z)))
+            .filter(|p| self.is_open(p.x, p.y))
+    }
+
+    /// The open cell closest to `to`, if any.
+    pub fn nearest_open(&self, to: Point) -> Option<Point> {
+        self.open_cells().min_by_key(|p| (p.x - to.x).abs() + (p.y - to.y).abs())
+    }
+
+    /// Scans from a random starting row for a run long enough to hold a staircase.
+    fn stair_site(&self, rng: &mut RandomNumberGenerator) -> Option<Point> {
+        let offset = rng.range(0, self.depth.max(1));
+        (0..self.depth).map(|i| (i + offset) % self.depth).find_map(|z| {
+            (0..self.width - STAIR_LENGTH - 5).find(|x| {
+                (0..=STAIR_LENGTH).all(|s| self.is_open(x + s, z) && self.is_open(x + s, z + 1))
+            }).map(|x| Point::new(x, z))
+        })
+    }
+}
+
+/// Carves one plan per storey into solid rock and links consecutive storeys with staircases.
+/// Players start on the first open cell of the bottom storey.
+pub fn carve_plans(grid: &mut TileGrid, rng: &mut RandomNumberGenerator, plans: &[FloorPlan], palette: DungeonPalette, progress: &GenProgress) {
+    if let Some(start) = plans.first().and_then(|plan| plan.open_cells().next()) {
+        grid.spawns.push(LevelSpawn::player(IVec3::new(start.x, 1, start.y)));
+    }
+    for (floor, plan) in plans.iter().enumerate() {
+        progress.report("Carving storeys", 0.5 + 0.4 * floor as f32 / plans.len() as f32);
+        let base = floor as i32 * FLOOR_HEIGHT;
+        for cell in plan.open_cells() {
+            carve_area(grid, Rect::with_exact(cell.x, cell.y, cell.x, cell.y), base, HEADROOM, palette.cave);
+        }
+    }
+    progress.report("Linking storeys", 0.9);
+    for (floor, pair) in plans.windows(2).enumerate() {
+        if let Some(site) = pair[0].stair_site(rng) {
+            let landing = carve_stairs(grid, site, floor as i32 * FLOOR_HEIGHT, palette);
+            if let Some(target) = pair[1].nearest_open(landing) {
+                carve_corridor(grid, rng, landing, target, (floor as i32 + 1) * FLOOR_HEIGHT, palette.cave);
+            }
+        }
+    }
+}
+
+/// Opens the inclusive XZ rectangle on the storey starting at `base`, laying `floor_mat` underfoot.
+pub fn carve_area(grid: &mut TileGrid, area: Rect, base: i32, height: i32, floor_mat: usize) {
+    grid.fill(IVec3::new(area.x1, base, area.y1), IVec3::new(area.x2, base, area.y2), Some(floor_mat));
+    grid.fill(IVec3::new(area.x1, base + 1, area.y1), IVec3::new(area.x2, base + height, area.y2), None);
+}
+
+/// Digs an L-shaped corridor between two points on the storey starting at `base`.
+pub fn carve_corridor(grid: &mut TileGrid, rng: &mut RandomNumberGenerator, from: Point, to: Point, base: i32, floor_mat: usize) {
+    let half = CORRIDOR_WIDTH / 2;
+    let horizontal = |x1: i32, x2: i32, z: i32| Rect::with_exact(x1.min(x2), z - half, x1.max(x2), z + half);
+    let vertical = |z1: i32, z2: i32, x: i32| Rect::with_exact(x - half, z1.min(z2), x + half, z1.max(z2));
+    let legs = if rng.range(0, 2) == 0 {
+        [horizontal(from.x, to.x, from.y), vertical(from.y, to.y, to.x)]
+    } else {
+        [vertical(from.y, to.y, from.x), horizontal(from.x, to.x, to.y)]
+    };
+    for leg in legs {
+        carve_area(grid, leg, base, HEADROOM, floor_mat);
+    }
+}
+
+/// Builds a two tile wide staircase rising along +X from `site` through the ceiling of the storey at `base`,
+/// and opens a landing at the top. Returns the centre of the landing on the storey above.
+pub fn carve_stairs(grid: &mut TileGrid, site: Point, base: i32, palette: DungeonPalette) -> Point {
+    let (x0, z0) = (site.x, site.y);
+    for step in 1..=STAIR_LENGTH {
+        let x = x0 + step;
+        grid.fill(IVec3::new(x, base + 1, z0), IVec3::new(x, base + step, z0 + 1), Some(palette.wall));
+        grid.fill(IVec3::new(x, base + step + 1, z0), IVec3::new(x, base + step + HEADROOM, z0 + 1), None);
+    }
+    let landing = Rect::with_exact(x0 + STAIR_LENGTH + 1, z0 - 1, x0 + STAIR_LENGTH + 4, z0 + 2);
+    carve_area(grid, landing, base + FLOOR_HEIGHT, HEADROOM, palette.floor);
+    landing.center()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PALETTE: DungeonPalette = DungeonPalette { wall: 0, floor: 1, cave: 2 };
+    const SIZE: IVec3 = IVec3::new(32, 16, 32);
+
+    fn tiles(config: &GeneratorConfig, seed: u64) -> Vec<Option<usize>> {
+        let grid = config.build().generate(seed, SIZE, PALETTE, &GenProgress::default());
+        grid.iter().map(|(_, tile)| tile).collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_grid() {
+        for name in GeneratorConfig::NAMES {
+            let config = GeneratorConfig::named(name).unwrap();
+            assert_eq!(tiles(&config, 7), tiles(&config, 7), "{name} is not deterministic");
+        }
+    }
+
+    #[test]
+    fn different_seeds_give_different_grids() {
+        let config = GeneratorConfig::default();
+        assert_ne!(tiles(&config, 1), tiles(&config, 2));
+    }
+
+    #[test]
+    fn named_generators_are_valid() {
+        for name in GeneratorConfig::NAMES {
+            assert_eq!(GeneratorConfig::named(name).unwrap().validate(SIZE), Ok(()), "{name}");
+        }
+        assert_eq!(GeneratorConfig::named("maze"), None);
+    }
+
+    #[test]
+    fn bad_parameters_are_rejected() {
+        let bad = [
+            GeneratorConfig::NoiseFill { frequency: f32::NAN },
+            GeneratorConfig::Bsp { min_leaf: 0, min_room: 5, caves: false },
+            GeneratorConfig::Bsp { min_leaf: 12, min_room: 0, caves: false },
+            GeneratorConfig::CellularCaves { fill_chance: 1.5, iterations: 4, scale: 2 },
+            GeneratorConfig::CellularCaves { fill_chance: 0.5, iterations: 4, scale: 0 },
+            GeneratorConfig::WaveFunctionCollapse { cell_size: 0 },
+        ];
+        for config in bad {
+            assert!(config.validate(SIZE).is_err(), "{config:?}");
+        }
+        let walk = GeneratorConfig::DrunkardsWalk { walkers: 1, steps: 10 };
+        assert!(walk.validate(IVec3::new(4, 16, 32)).is_err());
+        assert!(walk.validate(IVec3::new(DRUNKARD_MIN_WIDTH, 16, DRUNKARD_MIN_WIDTH)).is_ok());
+        // The smallest accepted level still generates.
+        walk.build().generate(0, IVec3::new(DRUNKARD_MIN_WIDTH, 8, DRUNKARD_MIN_WIDTH), PALETTE, &GenProgress::default());
+    }
+}
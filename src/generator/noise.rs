@@ -0,0 +1,35 @@
+use bevy::prelude::*;
+use rltk::FastNoise;
+
+use super::{DungeonGenerator, DungeonPalette, GenProgress, TileGrid};
+
+/// Fills the whole region with rock, banded into cave and floor material by Perlin noise.
+pub struct NoiseFill {
+    pub frequency: f32,
+}
+
+impl DungeonGenerator for NoiseFill {
+    fn generate(&self, seed: u64, size: IVec3, palette: DungeonPalette, progress: &GenProgress) -> TileGrid {
+        let mut noise = FastNoise::seeded(seed);
+        noise.set_noise_type(rltk::NoiseType::Perlin);
+        noise.set_frequency(self.frequency);
+        let mut grid = TileGrid::new(size, None);
+        for z in 0..size.z {
+            progress.report("Filling rock", z as f32 / size.z as f32);
+            for y in 0..size.y {
+                for x in 0..size.x {
+                    let val = noise.get_noise3d(x as f32, y as f32, z as f32) / 2.0 + 0.5;
+                    let tile = if val < 0.05 {
+                        palette.floor
+                    } else if val < 0.25 {
+                        palette.cave
+                    } else {
+                        palette.wall
+                    };
+                    grid.set(IVec3::new(x, y, z), Some(tile));
+                }
+            }
+        }
+        grid
+    }
+}
@@ -0,0 +1,166 @@
+use bevy::prelude::*;
+use rltk::RandomNumberGenerator;
+
+use super::{carve_plans, storey_count, DungeonGenerator, DungeonPalette, FloorPlan, GenProgress, TileGrid, CORRIDOR_WIDTH};
+
+const NORTH: u8 = 1;
+const EAST: u8 = 2;
+const SOUTH: u8 = 4;
+const WEST: u8 = 8;
+const ATTEMPTS: u32 = 10;
+
+/// A piece that can fill one cell: which edges it opens onto, whether it is a whole room, and how often to pick it.
+struct Piece {
+    edges: u8,
+    room: bool,
+    weight: f32,
+}
+
+/// Every edge combination as a corridor piece, plus a room that opens on all sides.
+fn pieces() -> Vec<Piece> {
+    let mut pieces: Vec<Piece> = (0..16u8).map(|edges| Piece {
+        edges,
+        room: false,
+        weight: match edges.count_ones() {
+            0 => 1.0,
+            1 => 0.3,
+            2 => 1.0,
+            3 => 0.6,
+            _ => 0.4,
+        },
+    }).collect();
+    pieces.push(Piece { edges: NORTH | EAST | SOUTH | WEST, room: true, weight: 0.5 });
+    pieces
+}
+
+/// Divides each storey into cells of `cell_size` tiles and collapses them into corridor and room pieces
+/// whose open edges always line up with their neighbours.
+pub struct WaveFunctionCollapse {
+    pub cell_size: i32,
+}
+
+impl DungeonGenerator for WaveFunctionCollapse {
+    fn generate(&self, seed: u64, size: IVec3, palette: DungeonPalette, progress: &GenProgress) -> TileGrid {
+        let mut rng = RandomNumberGenerator::seeded(seed);
+        let pieces = pieces();
+        let cs = self.cell_size.max(CORRIDOR_WIDTH + 1);
+        let (w, d) = ((size.x - 2) / cs, (size.z - 2) / cs);
+        let count = storey_count(size.y);
+        let plans: Vec<FloorPlan> = (0..count).map(|floor| {
+            progress.report("Planning storeys", 0.5 * floor as f32 / count as f32);
+            let mut plan = FloorPlan::new(size.x, size.z);
+            if let Some(cells) = (0..ATTEMPTS).find_map(|_| collapse(&mut rng, &pieces, w, d)) {
+                for (i, piece) in cells.iter().enumerate() {
+                    let (cx, cz) = (i as i32 % w, i as i32 / w);
+                    draw_piece(&mut plan, &pieces[*piece], 1 + cx * cs, 1 + cz * cs, cs);
+                }
+            }
+            plan
+        }).collect();
+
+        let mut grid = TileGrid::new(size, Some(palette.wall));
+        carve_plans(&mut grid, &mut rng, &plans, palette, progress);
+        grid
+    }
+}
+
+/// Runs one collapse over a `w` by `d` grid of cells, returning the chosen piece for each cell
+/// or `None` if the constraints contradicted each other.
+fn collapse(rng: &mut RandomNumberGenerator, pieces: &[Piece], w: i32, d: i32) -> Option<Vec<usize>> {
+    let all: u32 = (1 << pieces.len()) - 1;
+    let mut options = vec![all; (w * d) as usize];
+    // Nothing may lead off the edge of the map.
+    for z in 0..d {
+        for x in 0..w {
+            let mut closed = 0;
+            if z == 0 { closed |= NORTH; }
+            if x == w - 1 { closed |= EAST; }
+            if z == d - 1 { closed |= SOUTH; }
+            if x == 0 { closed |= WEST; }
+            let i = (x + z * w) as usize;
+            options[i] = filter(pieces, options[i], |p| p.edges & closed == 0);
+        }
+    }
+    loop {
+        let min = options.iter().map(|o| o.count_ones()).filter(|c| *c > 1).min();
+        let Some(min) = min else {
+            break;
+        };
+        let lowest: Vec<usize> = (0..options.len()).filter(|i| options[*i].count_ones() == min).collect();
+        let cell = lowest[rng.range(0, lowest.len())];
+        options[cell] = 1 << pick_weighted(rng, pieces, options[cell]);
+        if !propagate(pieces, &mut options, w, d, cell) {
+            return None;
+        }
+    }
+    options.iter().map(|o| (o.count_ones() == 1).then(|| o.trailing_zeros() as usize)).collect()
+}
+
+fn filter(pieces: &[Piece], options: u32, keep: impl Fn(&Piece) -> bool) -> u32 {
+    (0..pieces.len()).filter(|i| options & (1 << i) != 0 && keep(&pieces[*i])).fold(0, |acc, i| acc | 1 << i)
+}
+
+fn pick_weighted(rng: &mut RandomNumberGenerator, pieces: &[Piece], options: u32) -> usize {
+    let candidates: Vec<usize> = (0..pieces.len()).filter(|i| options & (1 << i) != 0).collect();
+    let total: f32 = candidates.iter().map(|i| pieces[*i].weight).sum();
+    let mut roll = rng.range(0.0, total);
+    for i in &candidates {
+        roll -= pieces[*i].weight;
+        if roll <= 0.0 {
+            return *i;
+        }
+    }
+    *candidates.last().unwrap()
+}
+
+/// Narrows neighbouring cells until every remaining option agrees with its neighbours' shared edges.
+fn propagate(pieces: &[Piece], options: &mut [u32], w: i32, d: i32, start: usize) -> bool {
+    let mut stack = vec![start];
+    while let Some(cell) = stack.pop() {
+        let (x, z) = (cell as i32 % w, cell as i32 / w);
+        for (dx, dz, out, back) in [(0, -1, NORTH, SOUTH), (1, 0, EAST, WEST), (0, 1, SOUTH, NORTH), (-1, 0, WEST, EAST)] {
+            let (nx, nz) = (x + dx, z + dz);
+            if nx < 0 || nz < 0 || nx >= w || nz >= d {
+                continue;
+            }
+            // Which states the shared edge can still be in from this side.
+            let can_open = filter(pieces, options[cell], |p| p.edges & out != 0) != 0;
+            let can_close = filter(pieces, options[cell], |p| p.edges & out == 0) != 0;
+            let n = (nx + nz * w) as usize;
+            let narrowed = filter(pieces, options[n], |p| if p.edges & back != 0 { can_open } else { can_close });
+            if narrowed != options[n] {
+                if narrowed == 0 {
+                    return false;
+                }
+                options[n] = narrowed;
+                stack.push(n);
+            }
+        }
+    }
+    true
+}
+
+/// Opens the piece's corridors (or room) in the plan cell whose corner is at `(ox, oz)`.
+fn draw_piece(plan: &mut FloorPlan, piece: &Piece, ox: i32, oz: i32, cs: i32) {
+    let c = cs / 2;
+    let half = CORRIDOR_WIDTH / 2;
+    if piece.room {
+        for z in 1..cs - 1 {
+            for x in 1..cs - 1 {
+                plan.set(ox + x, oz + z, true);
+            }
+        }
+    } else if piece.edges != 0 {
+        plan.open_brush(ox + c, oz + c, half);
+    }
+    let strips = [(NORTH, (c, 0), (c, c)), (EAST, (c, c), (cs - 1, c)), (SOUTH, (c, c), (c, cs - 1)), (WEST, (0, c), (c, c))];
+    for (edge, from, to) in strips {
+        if piece.edges & edge != 0 {
+            for z in from.1..=to.1 {
+                for x in from.0..=to.0 {
+                    plan.open_brush(ox + x, oz + z, half);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use bevy::prelude::*;
+
+use crate::{
+    actions::{Action, ActionState},
+    dungeon::{CmdDestroyTile, CmdSpawnTile, Level, LvlState},
+    material::{MaterialType, MaterialTypes},
+    player::{Player, PLAYER_HALF_EXTENTS},
+    raycast::{LookTarget, TileHit},
+};
+
+/// How far away the player can dig or build, in world units.
+pub const REACH: f32 = 4.0;
+/// Materials reachable from the hotbar, one per `Action::HotbarSlot`.
+pub const HOTBAR_SLOTS: usize = 9;
+
+/// Holding `Action::Dig` digs out the tile in `LookTarget`, taking its material's hardness in seconds.
+/// `Action::Place` places the hotbar's material against the face looked at. Slot actions and scrolling pick
+/// the material, as does `Action::Interact` on a tile of a material in the hotbar. Edits are spawned as
+/// tile commands, so they are replicated and recorded like any other.
+pub struct InteractPlugin;
+
+impl Plugin for InteractPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Hotbar>()
+            .init_resource::<Digging>()
+            .add_systems((select_hotbar, dig_tile, place_tile)
+                .distributive_run_if(resource_exists::<Input<MouseButton>>())
+                .in_set(OnUpdate(LvlState::Ready)));
+    }
+}
+
+/// Draws the hotbar along the bottom of the window.
+pub struct HotbarUiPlugin;
+
+impl Plugin for HotbarUiPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(draw_hotbar);
+    }
+}
+
+/// Which hotbar slot is selected. Slots hold the materials in id order.
+#[derive(Resource, Debug, Default)]
+pub struct Hotbar {
+    pub selected: usize,
+}
+
+impl Hotbar {
+    pub fn slots(mats: &MaterialTypes) -> impl Iterator<Item = &MaterialType> {
+        mats.iter().take(HOTBAR_SLOTS)
+    }
+
+    pub fn material<'a>(&self, mats: &'a MaterialTypes) -> Option<&'a MaterialType> {
+        Self::slots(mats).nth(self.selected)
+    }
+}
+
+/// The tile being dug out and for how long.
+#[derive(Resource, Debug, Default)]
+pub struct Digging {
+    pub target: Option<IVec3>,
+    pub elapsed: f32,
+}
+
+fn in_reach(look: &LookTarget) -> Option<TileHit> {
+    look.0.filter(|hit| hit.distance <= REACH)
+}
+
+fn select_hotbar(
+    mut hotbar: ResMut<Hotbar>,
+    actions: Res<ActionState>,
+    look: Res<LookTarget>,
+    lvl: Res<Level>,
+    mats: Res<MaterialTypes>,
+) {
+    let count = Hotbar::slots(&mats).count();
+    if count == 0 {
+        return;
+    }
+    let mut selected = hotbar.selected;
+    if let Some(slot) = (0..HOTBAR_SLOTS).position(|slot| actions.just_pressed(Action::HotbarSlot(slot as u8))) {
+        selected = slot;
+    }
+    if actions.just_pressed(Action::Interact) {
+        let looked_at = in_reach(&look).and_then(|hit| lvl.get(hit.pos));
+        if let Some(slot) = Hotbar::slots(&mats).position(|mat| Some(mat.id) == looked_at) {
+            selected = slot;
+        }
+    }
+    let scroll = actions.axis(Action::HotbarScroll).y;
+    if scroll > 0.0 {
+        selected = (selected + count - 1) % count;
+    } else if scroll < 0.0 {
+        selected = (selected + 1) % count;
+    }
+    selected = selected.min(count - 1);
+    // Only set when it changes, so the hotbar is redrawn only then.
+    if selected != hotbar.selected {
+        hotbar.selected = selected;
+    }
+}
+
+fn dig_tile(
+    mut commands: Commands,
+    mut digging: ResMut<Digging>,
+    actions: Res<ActionState>,
+    look: Res<LookTarget>,
+    lvl: Res<Level>,
+    mats: Res<MaterialTypes>,
+    time: Res<Time>,
+) {
+    let target = in_reach(&look).map(|hit| hit.pos).filter(|_| actions.pressed(Action::Dig));
+    if target != digging.target {
+        *digging = Digging { target, elapsed: 0.0 };
+    }
+    let Some(pos) = target else {
+        return;
+    };
+    let Some(mat) = lvl.get(pos).and_then(|id| mats.get(id)) else {
+        return;
+    };
+    digging.elapsed += time.delta_seconds();
+    if digging.elapsed >= mat.hardness {
+        commands.spawn(CmdDestroyTile { pos });
+        *digging = Digging::default();
+    }
+}
+
+fn place_tile(
+    mut commands: Commands,
+    actions: Res<ActionState>,
+    look: Res<LookTarget>,
+    hotbar: Res<Hotbar>,
+    lvl: Res<Level>,
+    mats: Res<MaterialTypes>,
+    players: Query<&GlobalTransform, With<Player>>,
+) {
+    if !actions.just_pressed(Action::Place) {
+        return;
+    }
+    let (Some(hit), Some(mat)) = (in_reach(&look), hotbar.material(&mats)) else {
+        return;
+    };
+    // A ray that starts inside a tile has no face to build against.
+    if hit.normal == IVec3::ZERO {
+        return;
+    }
+    let pos = hit.adjacent();
+    let centre = pos.as_vec3() * lvl.tile_scale;
+    let half = Vec3::splat(lvl.tile_scale / 2.0);
+    let blocked = players.iter().any(|transform| {
+        // Bounds of the player's rotated collider.
+        let (_, rotation, translation) = transform.to_scale_rotation_translation();
+        let m = Mat3::from_quat(rotation);
+        let extent = m.x_axis.abs() * PLAYER_HALF_EXTENTS.x + m.y_axis.abs() * PLAYER_HALF_EXTENTS.y + m.z_axis.abs() * PLAYER_HALF_EXTENTS.z;
+        ((centre - translation).abs() - half - extent).cmplt(Vec3::ZERO).all()
+    });
+    if !blocked {
+        commands.spawn(CmdSpawnTile { pos, mat: mat.id });
+    }
+}
+
+#[derive(Component)]
+struct HotbarUi;
+
+fn draw_hotbar(
+    mut commands: Commands,
+    hotbar: Res<Hotbar>,
+    mats: Res<MaterialTypes>,
+    roots: Query<Entity, With<HotbarUi>>,
+) {
+    if !hotbar.is_changed() && !mats.is_changed() {
+        return;
+    }
+    for root in &roots {
+        commands.entity(root).despawn_recursive();
+    }
+    commands.spawn((
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect { bottom: Val::Px(8.0), ..default() },
+                size: Size::width(Val::Percent(100.0)),
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+            ..default()
+        },
+        HotbarUi,
+    )).with_children(|bar| {
+        for (slot, mat) in Hotbar::slots(&mats).enumerate() {
+            let frame = if slot == hotbar.selected { Color::WHITE } else { Color::rgba(0.0, 0.0, 0.0, 0.6) };
+            bar.spawn(NodeBundle {
+                style: Style {
+                    size: Size::all(Val::Px(40.0)),
+                    margin: UiRect::all(Val::Px(2.0)),
+                    padding: UiRect::all(Val::Px(3.0)),
+                    ..default()
+                },
+                background_color: frame.into(),
+                ..default()
+            }).with_children(|frame| {
+                frame.spawn(NodeBundle {
+                    style: Style {
+                        size: Size::all(Val::Percent(100.0)),
+                        ..default()
+                    },
+                    background_color: mat.color.into(),
+                    ..default()
+                });
+            });
+        }
+    });
+}
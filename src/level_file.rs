@@ -0,0 +1,338 @@
+use std::{fmt, io::{self, Read, Write}};
+
+use bevy::prelude::*;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+use crate::{dungeon::{Level, LevelSpawn}, material::MaterialTypes};
+
+/// Identifies a level file.
+pub const LEVEL_FILE_MAGIC: &[u8; 4] = b"HXLV";
+/// Bumped whenever the layout below changes.
+pub const LEVEL_FILE_VERSION: u16 = 1;
+/// Most tiles a file's bounds may cover, so a corrupt header can't ask for a huge buffer.
+pub const LEVEL_FILE_MAX_TILES: usize = 1 << 26;
+
+// Layout, all integers little-endian:
+//
+//   magic "HXLV", version u16, then a zlib stream containing:
+//   size [u32; 3], tile_scale f32,
+//   bounds min [i32; 3], bounds extent [u32; 3],
+//   palette: u16 count, then that many strings,
+//   voxels: runs of (u32 length, u16 value) covering the bounds in x, y, z order,
+//           where value 0 is empty and n is palette entry n - 1,
+//   spawns: u32 count, then (string name, [i32; 3] pos) each.
+//
+// Strings are a u16 byte length followed by UTF-8.
+
+#[derive(Debug)]
+pub enum LevelFileError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Corrupt(&'static str),
+    UnknownMaterial(String),
+    /// The level's tiles are spread over more than `LEVEL_FILE_MAX_TILES`.
+    TooLarge,
+}
+
+impl fmt::Display for LevelFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::BadMagic => write!(f, "not a level file"),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported level file version {v}"),
+            Self::Corrupt(what) => write!(f, "corrupt level file: {what}"),
+            Self::UnknownMaterial(name) => write!(f, "level uses unknown material {name}"),
+            Self::TooLarge => write!(f, "level tiles span more than {LEVEL_FILE_MAX_TILES} tiles"),
+        }
+    }
+}
+
+impl std::error::Error for LevelFileError {}
+
+impl From<io::Error> for LevelFileError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// A decoded level file, independent of the materials loaded in the game.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelSnapshot {
+    pub size: [usize; 3],
+    pub tile_scale: f32,
+    pub palette: Vec<String>,
+    pub min: IVec3,
+    pub extent: IVec3,
+    /// Palette index per tile in the bounds, x fastest.
+    pub tiles: Vec<Option<u16>>,
+    pub spawns: Vec<LevelSpawn>,
+}
+
+impl LevelSnapshot {
+    /// Captures the level, naming each material so the file survives id changes.
+    pub fn capture(lvl: &Level, mats: &MaterialTypes) -> Result<Self, LevelFileError> {
+        let (min, max) = lvl.bounds().unwrap_or((IVec3::ZERO, IVec3::NEG_ONE));
+        // Tiles far apart would overflow i32 extents, or ask for a huge buffer.
+        let [x, y, z] = [0, 1, 2].map(|axis| (max[axis] as i64 - min[axis] as i64 + 1) as usize);
+        let count = x.checked_mul(y)
+            .and_then(|n| n.checked_mul(z))
+            .filter(|n| *n <= LEVEL_FILE_MAX_TILES)
+            .ok_or(LevelFileError::TooLarge)?;
+        // Within the limit, so each side fits an i32.
+        let extent = IVec3::new(x as i32, y as i32, z as i32);
+        let mut palette: Vec<String> = Vec::new();
+        let mut tiles = vec![None; count];
+        for (pos, id) in lvl.iter() {
+            let name = &mats.get(id).ok_or_else(|| LevelFileError::UnknownMaterial(format!("#{id}")))?.name;
+            let index = match palette.iter().position(|n| n == name) {
+                Some(index) => index,
+                None => {
+                    palette.push(name.clone());
+                    palette.len() - 1
+                }
+            };
+            let p = pos - min;
+            tiles[(p.x + p.y * extent.x + p.z * extent.x * extent.y) as usize] = Some(index as u16);
+        }
+        Ok(Self {
+            size: lvl.size,
+            tile_scale: lvl.tile_scale,
+            palette,
+            min,
+            extent,
+            tiles,
+            spawns: lvl.spawns.clone(),
+        })
+    }
+
+    /// Replaces the level's contents with the snapshot, mapping palette names to the current material ids.
+    pub fn apply(&self, lvl: &mut Level, mats: &MaterialTypes) -> Result<(), LevelFileError> {
+        let ids = self.palette.iter()
+            .map(|name| mats.get_mat(name).map(|m| m.id).ok_or_else(|| LevelFileError::UnknownMaterial(name.clone())))
+            .collect::<Result<Vec<_>, _>>()?;
+        lvl.clear();
+        lvl.size = self.size;
+        lvl.tile_scale = self.tile_scale;
+        lvl.spawns = self.spawns.clone();
+        let e = self.extent;
+        for (i, tile) in self.tiles.iter().enumerate() {
+            if let Some(index) = tile {
+                let i = i as i32;
+                let pos = self.min + IVec3::new(i % e.x, (i / e.x) % e.y, i / (e.x * e.y));
+                lvl.set(pos, Some(ids[*index as usize]));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, LevelFileError> {
+        let mut out = LEVEL_FILE_MAGIC.to_vec();
+        out.extend(LEVEL_FILE_VERSION.to_le_bytes());
+        let mut z = ZlibEncoder::new(out, Compression::default());
+        for s in self.size {
+            z.write_all(&(s as u32).to_le_bytes())?;
+        }
+        z.write_all(&self.tile_scale.to_le_bytes())?;
+        write_ivec3(&mut z, self.min)?;
+        write_ivec3(&mut z, self.extent)?;
+        z.write_all(&(self.palette.len() as u16).to_le_bytes())?;
+        for name in &self.palette {
+            write_str(&mut z, name)?;
+        }
+        let value = |t: &Option<u16>| t.map_or(0, |i| i + 1);
+        let mut tiles = self.tiles.iter().map(value).peekable();
+        while let Some(v) = tiles.next() {
+            let mut run: u32 = 1;
+            while tiles.next_if_eq(&v).is_some() {
+                run += 1;
+            }
+            z.write_all(&run.to_le_bytes())?;
+            z.write_all(&v.to_le_bytes())?;
+        }
+        z.write_all(&(self.spawns.len() as u32).to_le_bytes())?;
+        for spawn in &self.spawns {
+            write_str(&mut z, &spawn.name)?;
+            write_ivec3(&mut z, spawn.pos)?;
+        }
+        Ok(z.finish()?)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, LevelFileError> {
+        if bytes.len() < 6 || &bytes[..4] != LEVEL_FILE_MAGIC {
+            return Err(LevelFileError::BadMagic);
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != LEVEL_FILE_VERSION {
+            return Err(LevelFileError::UnsupportedVersion(version));
+        }
+        let mut z = ZlibDecoder::new(&bytes[6..]);
+        let size = [read_u32(&mut z)? as usize, read_u32(&mut z)? as usize, read_u32(&mut z)? as usize];
+        let tile_scale = f32::from_le_bytes(read_array(&mut z)?);
+        let min = read_ivec3(&mut z)?;
+        let extent = read_ivec3(&mut z)?;
+        if extent.cmplt(IVec3::ZERO).any() {
+            return Err(LevelFileError::Corrupt("negative extent"));
+        }
+        let palette = (0..u16::from_le_bytes(read_array(&mut z)?))
+            .map(|_| read_str(&mut z))
+            .collect::<Result<Vec<_>, _>>()?;
+        let count = (extent.x as usize).checked_mul(extent.y as usize)
+            .and_then(|n| n.checked_mul(extent.z as usize))
+            .filter(|n| *n <= LEVEL_FILE_MAX_TILES)
+            .ok_or(LevelFileError::Corrupt("bounds too large"))?;
+        let mut tiles = Vec::new();
+        while tiles.len() < count {
+            let run = read_u32(&mut z)? as usize;
+            let v = u16::from_le_bytes(read_array(&mut z)?);
+            if run == 0 || tiles.len() + run > count || v as usize > palette.len() {
+                return Err(LevelFileError::Corrupt("bad voxel run"));
+            }
+            tiles.extend(std::iter::repeat_n(v.checked_sub(1), run));
+        }
+        let spawns = (0..read_u32(&mut z)?)
+            .map(|_| Ok(LevelSpawn { name: read_str(&mut z)?, pos: read_ivec3(&mut z)? }))
+            .collect::<Result<Vec<_>, LevelFileError>>()?;
+        Ok(Self { size, tile_scale, palette, min, extent, tiles, spawns })
+    }
+}
+
+pub(crate) fn write_ivec3(w: &mut impl Write, v: IVec3) -> io::Result<()> {
+    for c in v.to_array() {
+        w.write_all(&c.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_str(w: &mut impl Write, s: &str) -> io::Result<()> {
+    w.write_all(&(s.len() as u16).to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+pub(crate) fn read_array<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub(crate) fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(r)?))
+}
+
+pub(crate) fn read_ivec3(r: &mut impl Read) -> io::Result<IVec3> {
+    Ok(IVec3::new(
+        i32::from_le_bytes(read_array(r)?),
+        i32::from_le_bytes(read_array(r)?),
+        i32::from_le_bytes(read_array(r)?),
+    ))
+}
+
+pub(crate) fn read_str(r: &mut impl Read) -> Result<String, LevelFileError> {
+    let len = u16::from_le_bytes(read_array(r)?) as usize;
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| LevelFileError::Corrupt("string is not UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::material::MaterialType;
+
+    use super::*;
+
+    fn materials() -> MaterialTypes {
+        let mat = |id, name: &str| MaterialType {
+            id,
+            name: name.into(),
+            color: Color::WHITE,
+            tile: None,
+            hardness: 1.0,
+            opacity: 1.0,
+            solid: true,
+            friction: 1.0,
+            liquid: false,
+            climbable: false,
+            hazardous: false,
+        };
+        MaterialTypes::new([mat(0, "Stone"), mat(1, "Dirt")]).unwrap()
+    }
+
+    fn level() -> Level {
+        let mut lvl = Level { size: [40, 8, 24], tile_scale: 0.25, ..default() };
+        // A long run of stone across a chunk border, a gap, then single dirt tiles.
+        for x in -3..30 {
+            lvl.set(IVec3::new(x, 2, 5), Some(0));
+        }
+        lvl.set(IVec3::new(4, 7, -2), Some(1));
+        lvl.set(IVec3::new(5, 7, -2), Some(0));
+        lvl.spawns.push(LevelSpawn::player(IVec3::new(1, 3, 5)));
+        lvl.spawns.push(LevelSpawn { name: "Exit".into(), pos: IVec3::new(20, 3, 5) });
+        lvl
+    }
+
+    fn tiles(lvl: &Level) -> Vec<([i32; 3], usize)> {
+        let mut tiles: Vec<_> = lvl.iter().map(|(pos, id)| (pos.to_array(), id)).collect();
+        tiles.sort_unstable();
+        tiles
+    }
+
+    #[test]
+    fn save_then_load_gives_the_same_level() {
+        let mats = materials();
+        let original = level();
+        let snapshot = LevelSnapshot::capture(&original, &mats).unwrap();
+        let decoded = LevelSnapshot::decode(&snapshot.encode().unwrap()).unwrap();
+        assert_eq!(decoded, snapshot);
+
+        let mut loaded = Level::default();
+        decoded.apply(&mut loaded, &mats).unwrap();
+        assert_eq!(tiles(&loaded), tiles(&original));
+        assert_eq!(loaded.spawns, original.spawns);
+        assert_eq!(loaded.size, original.size);
+        assert_eq!(loaded.tile_scale, original.tile_scale);
+    }
+
+    #[test]
+    fn empty_levels_round_trip() {
+        let mats = materials();
+        let snapshot = LevelSnapshot::capture(&Level::default(), &mats).unwrap();
+        assert_eq!(LevelSnapshot::decode(&snapshot.encode().unwrap()).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn oversized_bounds_are_rejected() {
+        let mut out = LEVEL_FILE_MAGIC.to_vec();
+        out.extend(LEVEL_FILE_VERSION.to_le_bytes());
+        let mut z = ZlibEncoder::new(out, Compression::default());
+        for s in [64u32, 16, 64] {
+            z.write_all(&s.to_le_bytes()).unwrap();
+        }
+        z.write_all(&0.5f32.to_le_bytes()).unwrap();
+        write_ivec3(&mut z, IVec3::ZERO).unwrap();
+        write_ivec3(&mut z, IVec3::splat(i32::MAX)).unwrap();
+        z.write_all(&0u16.to_le_bytes()).unwrap();
+        let bytes = z.finish().unwrap();
+        assert!(matches!(LevelSnapshot::decode(&bytes), Err(LevelFileError::Corrupt(_))));
+    }
+
+    #[test]
+    fn scattered_levels_are_too_large_to_capture() {
+        let mats = materials();
+        let mut far = Level::default();
+        far.set(IVec3::splat(-1000), Some(0));
+        far.set(IVec3::splat(1000), Some(0));
+        assert!(matches!(LevelSnapshot::capture(&far, &mats), Err(LevelFileError::TooLarge)));
+        let mut wide = Level::default();
+        wide.set(IVec3::new(i32::MIN, 0, 0), Some(0));
+        wide.set(IVec3::new(i32::MAX, 0, 0), Some(0));
+        assert!(matches!(LevelSnapshot::capture(&wide, &mats), Err(LevelFileError::TooLarge)));
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(matches!(LevelSnapshot::decode(b"PNG\x00\x01\x00"), Err(LevelFileError::BadMagic)));
+        let mut newer = LEVEL_FILE_MAGIC.to_vec();
+        newer.extend((LEVEL_FILE_VERSION + 1).to_le_bytes());
+        assert!(matches!(LevelSnapshot::decode(&newer), Err(LevelFileError::UnsupportedVersion(_))));
+    }
+}
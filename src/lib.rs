@@ -0,0 +1,188 @@
+//! The game as a set of plugins. The `hexentropy` binary assembles them into the full game or a
+//! headless server; tests and tools can build an `App` from just the ones they need.
+
+use bevy::{prelude::*, app::AppExit, asset::LoadState, utils::HashMap};
+use bevy_rapier3d::{prelude::{Collider, RapierConfiguration}, render::RapierDebugRenderPlugin};
+
+use crate::{
+    camera::CameraPlugin,
+    dungeon::{CmdLvlInit, Level, LvlRenderPlugin},
+    interact::HotbarUiPlugin,
+    material::MaterialTypes,
+    options::{GameOptions, NetMode},
+    player::{dress_players, player_body, NetLocal, Player, PlayerInput, PLAYER_HALF_EXTENTS, PLAYER_START},
+    progress::ProgressTitlePlugin,
+};
+
+pub mod tileset_1bit;
+pub mod actions;
+pub mod camera;
+pub mod character;
+pub mod chunk;
+pub mod collider;
+pub mod mesher;
+
+pub mod dungeon;
+pub mod generator;
+pub mod interact;
+pub mod level_file;
+pub mod material;
+pub mod net;
+pub mod options;
+pub mod player;
+pub mod progress;
+pub mod raycast;
+pub mod replay;
+pub mod sim;
+
+/// Loads the game's assets and, once they are in, spawns the local player and requests the first level
+/// as `GameOptions` describe.
+pub struct GamePlugin;
+
+impl Plugin for GamePlugin {
+    fn build(&self, app: &mut App) {
+        app.world.get_resource_or_insert_with(RapierConfiguration::default).gravity = Vec3::new(0., -9.8, 0.);
+        app.init_resource::<GameAssets>()
+            .init_resource::<GameOptions>()
+            .add_state::<AppState>()
+            .add_system(load_assets.in_schedule(OnEnter(AppState::Setup)))
+            .add_system(check_assets.in_set(OnUpdate(AppState::Setup)))
+            .add_system(setup.in_schedule(OnEnter(AppState::Run)));
+    }
+}
+
+/// Everything drawn: chunk and player meshes, the camera and its modes, the hotbar, the window title and collider outlines.
+/// Needs `DefaultPlugins`; headless apps leave it out.
+pub struct GameRenderPlugin;
+
+impl Plugin for GameRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ClearColor(Color::BLACK))
+            .add_plugin(RapierDebugRenderPlugin::default())
+            .add_plugin(LvlRenderPlugin)
+            .add_plugin(ProgressTitlePlugin)
+            .add_plugin(HotbarUiPlugin)
+            .add_plugin(CameraPlugin)
+            .add_system(load_meshes.in_schedule(OnEnter(AppState::Setup)))
+            .add_system(dress_players);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum AppState {
+    #[default]
+    Setup,
+    Run,
+}
+
+impl States for AppState {
+    type Iter = std::array::IntoIter<AppState, 2>;
+    fn variants() -> Self::Iter {
+        [AppState::Setup, AppState::Run].into_iter()
+    }
+}
+
+#[derive(Clone)]
+pub struct CombinedMesh {
+    pub mesh: Handle<Mesh>,
+    pub material: Handle<StandardMaterial>,
+}
+
+impl CombinedMesh {
+    pub fn get_mesh(&self) -> Handle<Mesh> {
+        self.mesh.clone()
+    }
+    pub fn get_material(&self) -> Handle<StandardMaterial> {
+        self.material.clone()
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct GameAssets {
+    pub meshes: HashMap<String, CombinedMesh>,
+    pub tile_materials: Handle<MaterialTypes>,
+}
+
+fn load_assets(mut assets: ResMut<GameAssets>, sets: Res<AssetServer>) {
+    // Load the tile materials.
+    assets.tile_materials = sets.load("tiles.materials.ron");
+    println!("Loading...");
+}
+
+fn load_meshes(
+    mut assets: ResMut<GameAssets>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    // Load the player.
+    assets.meshes.insert("Player".to_owned(),CombinedMesh {
+        mesh: meshes.add(Mesh::from(shape::Capsule {
+            radius: PLAYER_HALF_EXTENTS.x,
+            depth: (PLAYER_HALF_EXTENTS.y - PLAYER_HALF_EXTENTS.x) * 2.0,
+            ..default()
+        })),
+        material: materials.add(Color::GREEN.into()),
+    });
+}
+
+fn check_assets(mut next_state: ResMut<NextState<AppState>>, assets: Res<GameAssets>, sets: Res<AssetServer>, mut ev_app: EventWriter<AppExit>) {
+    match sets.get_load_state(assets.tile_materials.clone()) {
+        LoadState::Loaded => {
+            println!("Loaded...");
+            next_state.set(AppState::Run);
+        }
+        LoadState::Failed => {
+            println!("Failed.");
+            ev_app.send(AppExit);
+        }
+        LoadState::Unloaded => {
+            println!("Unloaded.");
+            ev_app.send(AppExit);
+        }
+        _ => {}
+    }
+}
+
+fn setup(mut commands: Commands, options: Res<GameOptions>, mut lvl: ResMut<Level>) {
+    println!("Spawning...");
+
+    // Local player, unless this is a dedicated server.
+    if options.mode != NetMode::Server {
+        commands.spawn((
+            Player {
+                id: 0,
+            },
+            PlayerInput::default(),
+            NetLocal,
+        ))
+            // Physics; `dress_players` adds the mesh and camera.
+            .insert(player_body(Transform::from_translation(PLAYER_START)));
+    }
+
+    // Ambient lighting for all.
+    commands.insert_resource(AmbientLight {
+        color: Color::WHITE,
+        brightness: 0.2,
+    });
+
+    // Floor.
+    commands.spawn((
+        Collider::cuboid(100.0 * 16.0, 0.1, 100.0 * 16.0),
+        TransformBundle::from(Transform::from_xyz(50.0 * 16.0, 0.0, 50.0 * 16.0)),
+    ));
+
+    // Initial level setup. Clients wait for the server's level instead.
+    lvl.size = options.size;
+    lvl.tile_scale = options.tile_scale;
+    if options.mode == NetMode::Client {
+        println!("Waiting for the level from the server...");
+    } else {
+        commands.spawn(
+            CmdLvlInit {
+                seed: options.seed,
+                generator: options.generator.clone(),
+            }
+        );
+    }
+    println!("Ready.");
+}
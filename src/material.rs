@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+
+use bevy::{prelude::*, asset::{AssetLoader, LoadContext, LoadedAsset}, reflect::TypeUuid, utils::BoxedFuture};
+use serde::Deserialize;
+
+use crate::dungeon::Level;
+
+/// Loads tile materials from `*.materials.ron` files and keeps the `MaterialTypes` resource in sync with them,
+/// including when the file is edited while the game runs.
+pub struct MaterialRegistryPlugin;
+
+impl Plugin for MaterialRegistryPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<MaterialTypes>()
+            .init_asset_loader::<MaterialTypesLoader>()
+            .init_resource::<MaterialTypes>()
+            .add_system(apply_material_types);
+    }
+}
+
+/// One entry of a materials file, as written by designers.
+#[derive(Deserialize)]
+struct MaterialDef {
+    id: usize,
+    name: String,
+    /// Hex colour such as `"808080"`.
+    color: String,
+    #[serde(default)]
+    tile: Option<usize>,
+    #[serde(default = "default_one")]
+    hardness: f32,
+    #[serde(default = "default_one")]
+    opacity: f32,
+    #[serde(default = "default_true")]
+    solid: bool,
+    #[serde(default = "default_friction")]
+    friction: f32,
+    #[serde(default)]
+    liquid: bool,
+    #[serde(default)]
+    climbable: bool,
+    #[serde(default)]
+    hazardous: bool,
+}
+
+fn default_one() -> f32 {
+    1.0
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_friction() -> f32 {
+    0.8
+}
+
+#[derive(Deserialize)]
+struct MaterialFile {
+    materials: Vec<MaterialDef>,
+}
+
+#[derive(Clone)]
+pub struct MaterialType {
+    pub id: usize,
+    pub name: String,
+    pub color: Color,
+    /// Index into `TileSet1Bit` used to draw the material in the UI.
+    pub tile: Option<usize>,
+    /// How long the material resists digging, in seconds at bare hands.
+    pub hardness: f32,
+    /// 1.0 hides whatever is behind the tile, 0.0 is fully see-through.
+    pub opacity: f32,
+    /// Blocks movement; otherwise characters pass through the tile.
+    pub solid: bool,
+    pub friction: f32,
+    pub liquid: bool,
+    pub climbable: bool,
+    /// Hurts whoever touches the tile.
+    pub hazardous: bool,
+}
+
+impl MaterialType {
+    pub fn is_opaque(&self) -> bool {
+        self.opacity >= 1.0
+    }
+}
+
+/// Every tile material, indexed by id and by name.
+#[derive(Resource, Clone, Default, TypeUuid)]
+#[uuid = "6c1f7a0e-5d2b-4c8e-9a43-2f1e8b7d9c15"]
+pub struct MaterialTypes {
+    map: HashMap<usize, MaterialType>,
+    names: HashMap<String, usize>,
+}
+
+impl MaterialTypes {
+    pub fn new(materials: impl IntoIterator<Item = MaterialType>) -> Result<Self, String> {
+        let mut types = Self::default();
+        for mat in materials {
+            if types.map.contains_key(&mat.id) {
+                return Err(format!("duplicate material id {}", mat.id));
+            }
+            if types.names.insert(mat.name.clone(), mat.id).is_some() {
+                return Err(format!("duplicate material name {}", mat.name));
+            }
+            types.map.insert(mat.id, mat);
+        }
+        Ok(types)
+    }
+
+    pub fn get(&self, id: usize) -> Option<&MaterialType> {
+        self.map.get(&id)
+    }
+
+    pub fn get_mat(&self, name: &str) -> Option<&MaterialType> {
+        self.names.get(name).and_then(|id| self.map.get(id))
+    }
+
+    /// True until materials have been loaded.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// All materials in ascending id order.
+    pub fn iter(&self) -> impl Iterator<Item = &MaterialType> {
+        let mut mats: Vec<&MaterialType> = self.map.values().collect();
+        mats.sort_by_key(|m| m.id);
+        mats.into_iter()
+    }
+
+    /// Vertex colour used for tiles of material `id`, with the material's opacity as alpha.
+    pub fn color(&self, id: usize) -> [f32; 4] {
+        self.map.get(&id).map_or(Color::FUCHSIA, |m| m.color.with_a(m.opacity)).as_rgba_f32()
+    }
+
+    /// Unknown materials are treated as opaque and solid so they stay visible and can't be fallen through.
+    pub fn is_opaque(&self, id: usize) -> bool {
+        self.map.get(&id).is_none_or(MaterialType::is_opaque)
+    }
+
+    pub fn is_solid(&self, id: usize) -> bool {
+        self.map.get(&id).is_none_or(|m| m.solid)
+    }
+}
+
+#[derive(Default)]
+pub struct MaterialTypesLoader;
+
+impl AssetLoader for MaterialTypesLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let file: MaterialFile = ron::de::from_bytes(bytes)?;
+            let materials = file.materials.into_iter().map(|def| {
+                let color = Color::hex(&def.color)
+                    .map_err(|e| bevy::asset::Error::msg(format!("material {}: {e}", def.name)))?;
+                Ok(MaterialType {
+                    id: def.id,
+                    name: def.name,
+                    color,
+                    tile: def.tile,
+                    hardness: def.hardness,
+                    opacity: def.opacity,
+                    solid: def.solid,
+                    friction: def.friction,
+                    liquid: def.liquid,
+                    climbable: def.climbable,
+                    hazardous: def.hazardous,
+                })
+            }).collect::<Result<Vec<_>, bevy::asset::Error>>()?;
+            let types = MaterialTypes::new(materials).map_err(bevy::asset::Error::msg)?;
+            load_context.set_default_asset(LoadedAsset::new(types));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["materials.ron"]
+    }
+}
+
+/// Copies a loaded or reloaded materials file into the resource and recolours the level.
+fn apply_material_types(
+    mut events: EventReader<AssetEvent<MaterialTypes>>,
+    assets: Res<Assets<MaterialTypes>>,
+    mut mats: ResMut<MaterialTypes>,
+    mut lvl: ResMut<Level>,
+) {
+    for event in events.iter() {
+        if let AssetEvent::Created { handle } | AssetEvent::Modified { handle } = event {
+            if let Some(types) = assets.get(handle) {
+                *mats = types.clone();
+                lvl.mark_all_dirty();
+                println!("Loaded {} materials.", mats.map.len());
+            }
+        }
+    }
+}
+
+/// A solid material for tests that need tiles.
+#[cfg(test)]
+fn solid(id: usize, name: &str) -> MaterialType {
+    MaterialType {
+        id,
+        name: name.into(),
+        color: Color::GRAY,
+        tile: None,
+        hardness: 1.0,
+        opacity: 1.0,
+        solid: true,
+        friction: 1.0,
+        liquid: false,
+        climbable: false,
+        hazardous: false,
+    }
+}
+
+/// Just stone, with id 0.
+#[cfg(test)]
+pub(crate) fn stone() -> MaterialTypes {
+    MaterialTypes::new([solid(0, "Stone")]).unwrap()
+}
+
+/// The materials dungeon generation builds with.
+#[cfg(test)]
+pub(crate) fn dungeon_materials() -> MaterialTypes {
+    MaterialTypes::new([solid(0, "Stone"), solid(1, "Wood"), solid(2, "Dirt")]).unwrap()
+}
@@ -0,0 +1,205 @@
+use bevy::{prelude::*, render::{mesh::Indices, render_resource::PrimitiveTopology}};
+
+use crate::chunk::CHUNK_SIZE;
+
+/// Raw vertex data for one chunk, kept separate from `Mesh` so it can be built and inspected without a renderer.
+#[derive(Default)]
+pub struct ChunkMeshData {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub colors: Vec<[f32; 4]>,
+    pub indices: Vec<u32>,
+}
+
+impl ChunkMeshData {
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    pub fn quad_count(&self) -> usize {
+        self.indices.len() / 6
+    }
+
+    pub fn into_mesh(self) -> Mesh {
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, self.positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, self.normals);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, self.colors);
+        mesh.set_indices(Some(Indices::U32(self.indices)));
+        mesh
+    }
+
+    fn push_quad(&mut self, corners: [Vec3; 4], normal: Vec3, color: [f32; 4], tile_scale: f32) {
+        let base = self.positions.len() as u32;
+        for corner in corners {
+            self.positions.push((corner * tile_scale).into());
+            self.normals.push(normal.into());
+            self.colors.push(color);
+        }
+        if normal.max_element() > 0.0 {
+            self.indices.extend([base, base + 1, base + 2, base, base + 2, base + 3]);
+        } else {
+            self.indices.extend([base, base + 2, base + 1, base, base + 3, base + 2]);
+        }
+    }
+}
+
+/// Builds the visible surface of a chunk, merging coplanar faces of the same material into larger quads.
+///
+/// `sample` is queried with chunk-local coordinates, including one tile past each border so faces
+/// hidden by neighbouring chunks are culled. `face` decides whether a tile of the first material shows
+/// a face towards the neighbouring tile. Vertices are in world units relative to the chunk's
+/// origin corner, i.e. tile `(0, 0, 0)` spans `0..tile_scale` on each axis.
+pub fn greedy_mesh(
+    sample: impl Fn(IVec3) -> Option<usize>,
+    face: impl Fn(usize, Option<usize>) -> bool,
+    color: impl Fn(usize) -> [f32; 4],
+    tile_scale: f32,
+) -> ChunkMeshData {
+    let n = CHUNK_SIZE;
+    let mut data = ChunkMeshData::default();
+    let mut mask: Vec<Option<usize>> = vec![None; (n * n) as usize];
+    for d in 0..3 {
+        let u = (d + 1) % 3;
+        let v = (d + 2) % 3;
+        let mut step = IVec3::ZERO;
+        step[d] = 1;
+        for dir in [-1, 1] {
+            let normal = (step * dir).as_vec3();
+            for slice in 0..n {
+                // Collect faces on this slice that are not hidden by their neighbour.
+                for j in 0..n {
+                    for i in 0..n {
+                        let mut pos = IVec3::ZERO;
+                        pos[d] = slice;
+                        pos[u] = i;
+                        pos[v] = j;
+                        mask[(i + j * n) as usize] = match sample(pos) {
+                            Some(mat) if face(mat, sample(pos + step * dir)) => Some(mat),
+                            _ => None,
+                        };
+                    }
+                }
+                // Greedily grow rectangles of identical faces.
+                for j in 0..n {
+                    let mut i = 0;
+                    while i < n {
+                        let Some(mat) = mask[(i + j * n) as usize] else {
+                            i += 1;
+                            continue;
+                        };
+                        let mut w = 1;
+                        while i + w < n && mask[(i + w + j * n) as usize] == Some(mat) {
+                            w += 1;
+                        }
+                        let mut h = 1;
+                        'grow: while j + h < n {
+                            for k in 0..w {
+                                if mask[(i + k + (j + h) * n) as usize] != Some(mat) {
+                                    break 'grow;
+                                }
+                            }
+                            h += 1;
+                        }
+                        for y in 0..h {
+                            for x in 0..w {
+                                mask[(i + x + (j + y) * n) as usize] = None;
+                            }
+                        }
+
+                        let mut origin = Vec3::ZERO;
+                        origin[d] = (slice + if dir > 0 { 1 } else { 0 }) as f32;
+                        origin[u] = i as f32;
+                        origin[v] = j as f32;
+                        let mut du = Vec3::ZERO;
+                        du[u] = w as f32;
+                        let mut dv = Vec3::ZERO;
+                        dv[v] = h as f32;
+                        data.push_quad([origin, origin + du, origin + du + dv, origin + dv], normal, color(mat), tile_scale);
+                        i += w;
+                    }
+                }
+            }
+        }
+    }
+    data
+}
+
+/// Face rule for the opaque pass: opaque tiles show faces towards anything they cannot hide behind.
+pub fn opaque_face(opaque: impl Fn(usize) -> bool) -> impl Fn(usize, Option<usize>) -> bool {
+    move |mat, neighbour| opaque(mat) && !neighbour.is_some_and(&opaque)
+}
+
+/// Face rule for the translucent pass: see-through tiles show faces towards empty space and
+/// other see-through materials, but not between two tiles of the same material.
+pub fn translucent_face(opaque: impl Fn(usize) -> bool) -> impl Fn(usize, Option<usize>) -> bool {
+    move |mat, neighbour| !opaque(mat) && neighbour.is_none_or(|n| n != mat && !opaque(n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STONE: usize = 0;
+    const DIRT: usize = 1;
+    const GLASS: usize = 2;
+
+    fn opaque(id: usize) -> bool {
+        id != GLASS
+    }
+
+    fn mesh(tiles: &[(IVec3, usize)], face: impl Fn(usize, Option<usize>) -> bool) -> ChunkMeshData {
+        let sample = |pos| tiles.iter().find(|(p, _)| *p == pos).map(|(_, id)| *id);
+        greedy_mesh(sample, face, |_| [1.0; 4], 1.0)
+    }
+
+    #[test]
+    fn single_tile_has_six_faces() {
+        let data = mesh(&[(IVec3::ZERO, STONE)], opaque_face(opaque));
+        assert_eq!(data.quad_count(), 6);
+        assert_eq!(data.positions.len(), 24);
+    }
+
+    #[test]
+    fn faces_against_the_next_chunk_are_culled() {
+        let edge = IVec3::new(CHUNK_SIZE - 1, 0, 0);
+        assert_eq!(mesh(&[(edge, STONE)], opaque_face(opaque)).quad_count(), 6);
+        // The neighbour is outside this chunk's range, so only its sample hides the face.
+        let data = mesh(&[(edge, STONE), (edge + IVec3::X, STONE)], opaque_face(opaque));
+        assert_eq!(data.quad_count(), 5);
+        assert!(data.normals.iter().all(|n| *n != [1.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn coplanar_faces_merge_per_material() {
+        let n = CHUNK_SIZE;
+        let slab: Vec<_> = (0..n).flat_map(|x| (0..n).map(move |z| (IVec3::new(x, 0, z), STONE))).collect();
+        assert_eq!(mesh(&slab, opaque_face(opaque)).quad_count(), 6);
+
+        let split: Vec<_> = slab.iter().map(|&(p, _)| (p, if p.x < n / 2 { STONE } else { DIRT })).collect();
+        // Top, bottom and both Z sides split at the material boundary; the X sides don't cross it.
+        assert_eq!(mesh(&split, opaque_face(opaque)).quad_count(), 10);
+    }
+
+    #[test]
+    fn translucent_faces_hide_between_the_same_material() {
+        let pair = [(IVec3::ZERO, GLASS), (IVec3::X, GLASS)];
+        assert_eq!(mesh(&pair, translucent_face(opaque)).quad_count(), 6);
+        assert!(mesh(&pair, opaque_face(opaque)).is_empty());
+
+        let mixed = [(IVec3::ZERO, STONE), (IVec3::X, GLASS)];
+        // Stone shows through glass, but glass has no face against stone.
+        assert_eq!(mesh(&mixed, opaque_face(opaque)).quad_count(), 6);
+        assert_eq!(mesh(&mixed, translucent_face(opaque)).quad_count(), 5);
+    }
+
+    #[test]
+    fn triangles_wind_counter_clockwise_towards_their_normal() {
+        let data = mesh(&[(IVec3::ZERO, STONE)], opaque_face(opaque));
+        for tri in data.indices.chunks(3) {
+            let [a, b, c] = [tri[0], tri[1], tri[2]].map(|i| Vec3::from(data.positions[i as usize]));
+            let normal = Vec3::from(data.normals[tri[0] as usize]);
+            assert!((b - a).cross(c - a).dot(normal) > 0.0, "triangle {tri:?} faces away from {normal}");
+        }
+    }
+}
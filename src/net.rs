@@ -0,0 +1,1064 @@
+use std::{collections::VecDeque, io, net::{SocketAddr, UdpSocket}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use bevy::prelude::*;
+use bevy_renet::{
+    renet::{
+        transport::{ClientAuthentication, NetcodeClientTransport, NetcodeServerTransport, NetcodeTransportError, ServerAuthentication, ServerConfig},
+        ConnectionConfig, DefaultChannel, RenetClient, RenetServer, ServerEvent,
+    },
+    transport::{client_connected, NetcodeClientPlugin, NetcodeServerPlugin},
+    RenetClientPlugin, RenetServerPlugin,
+};
+use bevy_rapier3d::prelude::{Collider, KinematicCharacterController, MoveShapeOptions, QueryFilter, RapierConfiguration, RapierContext};
+use rltk::RandomNumberGenerator;
+
+use crate::{
+    character::{step_character, CharacterMovement},
+    dungeon::{CmdDestroyTile, CmdDestroyTileRect, CmdLvlSnapshot, CmdSpawnTile, Level, LvlState, QueuedTileCommands, TileEdit},
+    level_file::{read_array, read_ivec3, write_ivec3, LevelSnapshot},
+    interact::REACH,
+    material::MaterialTypes,
+    sim::{SimSet, SimTick},
+    player::{player_body, player_movement, player_spawn, NetLocal, Player, PlayerInput, Remote, PLAYER_HALF_EXTENTS, PLAYER_START},
+};
+
+/// Clients and servers only talk to each other when they agree on this.
+pub const PROTOCOL_ID: u64 = 0x4858_4e45_0001;
+pub const DEFAULT_PORT: u16 = 7878;
+pub const MAX_CLIENTS: usize = 16;
+/// How many of its newest input frames a client sends each frame, so one lost packet loses no input.
+const INPUT_REDUNDANCY: usize = 4;
+/// Input frames the server holds per client before dropping the oldest to catch up.
+const MAX_QUEUED_INPUTS: usize = 8;
+/// Predicted frames a client remembers while waiting for the server to confirm them.
+const MAX_PREDICTED: usize = 128;
+/// Prediction errors below this distance are not worth correcting.
+const RECONCILE_EPSILON: f32 = 0.01;
+/// Furthest from the centre of their player that a client may edit tiles. Aiming reaches `REACH`
+/// from the eye, which sits above the centre.
+const EDIT_REACH: f32 = REACH + PLAYER_HALF_EXTENTS.y;
+/// Most tiles a client may clear with one `DestroyRect`.
+const MAX_EDIT_RECT_TILES: i64 = 4096;
+/// Furthest a client may turn in one tick, in radians on each axis.
+const MAX_AIM_PER_TICK: f32 = std::f32::consts::FRAC_PI_2;
+
+/// Runs the game as a server, a client, or both in one process.
+/// Nothing is networked until a `CmdNetServe` or `CmdNetConnect` is spawned.
+pub struct NetPlugin;
+
+impl Plugin for NetPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(RenetServerPlugin)
+            .add_plugin(RenetClientPlugin)
+            .add_plugin(NetcodeServerPlugin)
+            .add_plugin(NetcodeClientPlugin)
+            .init_resource::<Prediction>()
+            .init_resource::<PendingLevels>()
+            .configure_set(NetSet::Server.run_if(resource_exists::<RenetServer>()))
+            .configure_set(NetSet::Client.run_if(client_connected))
+            .add_systems((start_server, start_client, log_transport_errors))
+            .add_systems((
+                server_connections,
+                server_send_players,
+                server_receive_tiles,
+                server_send_edits,
+                server_send_level.after(server_connections).after(server_send_edits).run_if(in_state(LvlState::Ready)),
+            ).in_set(NetSet::Server))
+            .add_system(server_apply_input
+                .run_if(resource_exists::<RenetServer>())
+                .before(player_movement)
+                .in_base_set(SimSet::Input)
+                .in_schedule(CoreSchedule::FixedUpdate))
+            .add_system(resend_level.in_schedule(OnEnter(LvlState::Ready)).run_if(resource_exists::<RenetServer>()))
+            .add_system(client_receive.in_set(NetSet::Client))
+            .add_systems((client_reconcile.before(client_send_input), client_send_input.before(player_movement), client_forward_tiles)
+                .distributive_run_if(client_connected)
+                .in_base_set(SimSet::Input)
+                .in_schedule(CoreSchedule::FixedUpdate))
+            .add_system(client_record_prediction
+                .run_if(client_connected)
+                .in_base_set(SimSet::Late)
+                .in_schedule(CoreSchedule::FixedUpdate));
+    }
+}
+
+/// Per-frame systems that run while serving or while connected to a server.
+/// Those that take part in a tick run in `CoreSchedule::FixedUpdate` instead.
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NetSet {
+    Server,
+    Client,
+}
+
+/// Starts serving the game on `addr`. The local player stays player 0.
+#[derive(Component)]
+pub struct CmdNetServe {
+    pub addr: SocketAddr,
+}
+
+/// Connects to the server at `addr`, which then owns the local player.
+#[derive(Component)]
+pub struct CmdNetConnect {
+    pub addr: SocketAddr,
+}
+
+/// Marks a tile command the server already knows about, so a client doesn't send it back.
+#[derive(Component)]
+pub struct Replicated;
+
+/// Clients that need the whole level before the next tile edit makes sense to them.
+#[derive(Resource, Default)]
+struct PendingLevels(Vec<u64>);
+
+/// One frame of a client's input, stamped with the client's tick.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputFrame {
+    pub tick: u64,
+    pub input: PlayerInput,
+}
+
+impl InputFrame {
+    /// The frame's input with movement cut to unit length and aiming to `MAX_AIM_PER_TICK`,
+    /// so a client can't move or turn faster than its own controls would.
+    fn limited(&self) -> PlayerInput {
+        PlayerInput {
+            movement: self.input.movement.map(|m| m.clamp_length_max(1.0)),
+            aiming: self.input.aiming.map(|a| a.clamp(Vec2::splat(-MAX_AIM_PER_TICK), Vec2::splat(MAX_AIM_PER_TICK))),
+            jump: self.input.jump,
+        }
+    }
+}
+
+/// Input frames the server has received from a remote player but not yet applied.
+#[derive(Component, Default)]
+pub struct InputQueue {
+    frames: VecDeque<InputFrame>,
+    /// Newest tick received, so resent frames are only queued once.
+    received: u64,
+    /// Newest tick handed to the player. Ticks run before states are sent, so states include it.
+    applied: u64,
+}
+
+/// The local player's inputs sent to a server, and where each was predicted to leave them.
+#[derive(Resource, Default)]
+pub struct Prediction {
+    history: VecDeque<Predicted>,
+    /// The server's newest state for the local player, reconciled with at the start of the next tick.
+    confirmed: Option<PlayerState>,
+}
+
+struct Predicted {
+    tick: u64,
+    input: PlayerInput,
+    /// Facing when the input was applied, which decides where it walks.
+    rotation: Quat,
+    /// Where the tick left the player, and how it left them moving.
+    translation: Vec3,
+    movement: CharacterMovement,
+}
+
+impl Prediction {
+    /// Rewinds to the server's `state` after input `state.ack` and replays the inputs it hasn't applied yet,
+    /// returning where they leave the player and how it is moving, or `None` if the prediction was right.
+    /// `move_shape` moves the player's shape from a position by a translation, returning how far it got
+    /// and whether it ended up grounded.
+    pub fn reconcile(
+        &mut self,
+        state: &PlayerState,
+        dt: f32,
+        gravity: f32,
+        mut move_shape: impl FnMut(Vec3, Vec3) -> (Vec3, bool),
+    ) -> Option<(Vec3, CharacterMovement)> {
+        while self.history.front().is_some_and(|p| p.tick < state.ack) {
+            self.history.pop_front();
+        }
+        self.history.front().filter(|p| p.tick == state.ack)?;
+        let confirmed = self.history.pop_front()?;
+        if (state.translation - confirmed.translation).length() < RECONCILE_EPSILON {
+            return None;
+        }
+        let mut translation = state.translation;
+        let mut movement = CharacterMovement {
+            velocity: state.velocity,
+            vertical_velocity: state.vertical_velocity,
+            grounded: state.grounded,
+            ..confirmed.movement
+        };
+        for p in &mut self.history {
+            // Forward is -Z, as in `player_movement`.
+            movement.requested = p.input.movement.map(|m| p.rotation * Vec3::new(m.x, 0.0, -m.y));
+            movement.jump_requested = p.input.jump;
+            let (moved, grounded) = move_shape(translation, step_character(&mut movement, dt, gravity));
+            translation += moved;
+            p.translation = translation;
+            p.movement = movement.clone();
+            movement.grounded = grounded;
+        }
+        Some((translation, movement))
+    }
+}
+
+/// Everything needed to show a player on another machine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlayerState {
+    pub id: u64,
+    /// Newest input tick from this player that the state includes.
+    pub ack: u64,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub velocity: Vec3,
+    pub vertical_velocity: f32,
+    pub grounded: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerMessage {
+    /// Latest state of every player, sent unreliably every frame.
+    Players(Vec<PlayerState>),
+    PlayerLeft { id: u64 },
+    /// The whole level as an encoded `LevelSnapshot`, sent on join and whenever the level is replaced.
+    Level(Vec<u8>),
+    /// Tile edits made since, in the order the server made them.
+    Tiles(Vec<TileEdit>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+    /// The client's newest input frames, oldest first.
+    Input(Vec<InputFrame>),
+    /// Tile edits the client wants the server to make.
+    Tiles(Vec<TileEdit>),
+}
+
+// Messages are a u8 tag followed by little-endian fields, like the level file.
+
+impl ServerMessage {
+    /// Fails if a tile edit's material id doesn't fit the message.
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        match self {
+            Self::Players(players) => {
+                out.push(0);
+                out.extend((players.len() as u16).to_le_bytes());
+                for p in players {
+                    out.extend(p.id.to_le_bytes());
+                    out.extend(p.ack.to_le_bytes());
+                    write_vec3(&mut out, p.translation);
+                    for c in p.rotation.to_array() {
+                        out.extend(c.to_le_bytes());
+                    }
+                    write_vec3(&mut out, p.velocity);
+                    out.extend(p.vertical_velocity.to_le_bytes());
+                    out.push(p.grounded as u8);
+                }
+            }
+            Self::PlayerLeft { id } => {
+                out.push(1);
+                out.extend(id.to_le_bytes());
+            }
+            Self::Level(bytes) => {
+                out.push(2);
+                out.extend(bytes);
+            }
+            Self::Tiles(edits) => {
+                out.push(3);
+                write_edits(&mut out, edits)?;
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn decode(mut bytes: &[u8]) -> io::Result<Self> {
+        let r = &mut bytes;
+        match read_u8(r)? {
+            0 => {
+                let count = u16::from_le_bytes(read_array(r)?);
+                let players = (0..count).map(|_| Ok(PlayerState {
+                    id: read_u64(r)?,
+                    ack: read_u64(r)?,
+                    translation: read_vec3(r)?,
+                    rotation: Quat::from_array([read_f32(r)?, read_f32(r)?, read_f32(r)?, read_f32(r)?]),
+                    velocity: read_vec3(r)?,
+                    vertical_velocity: read_f32(r)?,
+                    grounded: read_u8(r)? != 0,
+                })).collect::<io::Result<_>>()?;
+                Ok(Self::Players(players))
+            }
+            1 => Ok(Self::PlayerLeft { id: read_u64(r)? }),
+            2 => Ok(Self::Level(r.to_vec())),
+            3 => Ok(Self::Tiles(read_edits(r)?)),
+            tag => Err(bad_tag(tag)),
+        }
+    }
+}
+
+impl ClientMessage {
+    /// Fails if a tile edit's material id doesn't fit the message.
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        match self {
+            Self::Input(frames) => {
+                out.push(0);
+                out.push(frames.len() as u8);
+                for frame in frames {
+                    out.extend(frame.tick.to_le_bytes());
+                    write_opt_vec2(&mut out, frame.input.movement);
+                    write_opt_vec2(&mut out, frame.input.aiming);
+                    out.push(frame.input.jump as u8);
+                }
+            }
+            Self::Tiles(edits) => {
+                out.push(1);
+                write_edits(&mut out, edits)?;
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn decode(mut bytes: &[u8]) -> io::Result<Self> {
+        let r = &mut bytes;
+        match read_u8(r)? {
+            0 => {
+                let count = read_u8(r)?;
+                let frames = (0..count).map(|_| Ok(InputFrame {
+                    tick: read_u64(r)?,
+                    input: PlayerInput { movement: read_opt_vec2(r)?, aiming: read_opt_vec2(r)?, jump: read_u8(r)? != 0 },
+                })).collect::<io::Result<_>>()?;
+                Ok(Self::Input(frames))
+            }
+            1 => Ok(Self::Tiles(read_edits(r)?)),
+            tag => Err(bad_tag(tag)),
+        }
+    }
+}
+
+fn bad_tag(tag: u8) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("unknown message tag {tag}"))
+}
+
+fn write_edits(out: &mut Vec<u8>, edits: &[TileEdit]) -> io::Result<()> {
+    out.extend((edits.len() as u32).to_le_bytes());
+    for edit in edits {
+        match *edit {
+            TileEdit::Spawn { pos, mat } => {
+                let mat = u16::try_from(mat)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("material id {mat} is too big to send")))?;
+                out.push(0);
+                write_ivec3(out, pos)?;
+                out.extend(mat.to_le_bytes());
+            }
+            TileEdit::Destroy { pos } => {
+                out.push(1);
+                write_ivec3(out, pos)?;
+            }
+            TileEdit::DestroyRect { min, max } => {
+                out.push(2);
+                write_ivec3(out, min)?;
+                write_ivec3(out, max)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_edits(r: &mut &[u8]) -> io::Result<Vec<TileEdit>> {
+    let count = u32::from_le_bytes(read_array(r)?);
+    (0..count).map(|_| Ok(match read_u8(r)? {
+        0 => TileEdit::Spawn { pos: read_ivec3(r)?, mat: u16::from_le_bytes(read_array(r)?) as usize },
+        1 => TileEdit::Destroy { pos: read_ivec3(r)? },
+        2 => TileEdit::DestroyRect { min: read_ivec3(r)?, max: read_ivec3(r)? },
+        tag => return Err(bad_tag(tag)),
+    })).collect()
+}
+
+fn write_vec3(out: &mut Vec<u8>, v: Vec3) {
+    for c in v.to_array() {
+        out.extend(c.to_le_bytes());
+    }
+}
+
+fn write_opt_vec2(out: &mut Vec<u8>, v: Option<Vec2>) {
+    match v {
+        Some(v) => {
+            out.push(1);
+            out.extend(v.x.to_le_bytes());
+            out.extend(v.y.to_le_bytes());
+        }
+        None => out.push(0),
+    }
+}
+
+fn read_u8(r: &mut &[u8]) -> io::Result<u8> {
+    Ok(read_array::<1>(r)?[0])
+}
+
+fn read_u64(r: &mut &[u8]) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(read_array(r)?))
+}
+
+fn read_f32(r: &mut &[u8]) -> io::Result<f32> {
+    Ok(f32::from_le_bytes(read_array(r)?))
+}
+
+fn read_vec3(r: &mut &[u8]) -> io::Result<Vec3> {
+    Ok(Vec3::new(read_f32(r)?, read_f32(r)?, read_f32(r)?))
+}
+
+/// Reads an optional vector, refusing infinities and NaN so they can't reach a player's movement.
+fn read_opt_vec2(r: &mut &[u8]) -> io::Result<Option<Vec2>> {
+    Ok(match read_u8(r)? {
+        0 => None,
+        _ => {
+            let v = Vec2::new(read_f32(r)?, read_f32(r)?);
+            if !v.is_finite() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "non-finite input"));
+            }
+            Some(v)
+        }
+    })
+}
+
+/// Sends `message` to `client_id`, or to every client if `None`.
+fn server_send(server: &mut RenetServer, client_id: Option<u64>, channel: DefaultChannel, message: &ServerMessage) {
+    match message.encode() {
+        Ok(bytes) => match client_id {
+            Some(client_id) => server.send_message(client_id, channel, bytes),
+            None => server.broadcast_message(channel, bytes),
+        },
+        Err(e) => println!("Failed to send to clients: {e}"),
+    }
+}
+
+fn client_send(client: &mut RenetClient, channel: DefaultChannel, message: &ClientMessage) {
+    match message.encode() {
+        Ok(bytes) => client.send_message(channel, bytes),
+        Err(e) => println!("Failed to send to the server: {e}"),
+    }
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+pub fn new_server(addr: SocketAddr) -> io::Result<(RenetServer, NetcodeServerTransport)> {
+    let socket = UdpSocket::bind(addr)?;
+    let config = ServerConfig {
+        max_clients: MAX_CLIENTS,
+        protocol_id: PROTOCOL_ID,
+        public_addr: socket.local_addr()?,
+        authentication: ServerAuthentication::Unsecure,
+    };
+    let transport = NetcodeServerTransport::new(now(), config, socket)?;
+    Ok((RenetServer::new(ConnectionConfig::default()), transport))
+}
+
+pub fn new_client(server_addr: SocketAddr) -> io::Result<(RenetClient, NetcodeClientTransport)> {
+    let bind: SocketAddr = if server_addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+    let socket = UdpSocket::bind(bind)?;
+    let time = now();
+    let authentication = ClientAuthentication::Unsecure {
+        protocol_id: PROTOCOL_ID,
+        // Random so clients starting together don't collide. Player 0 is the server's own player.
+        client_id: RandomNumberGenerator::new().next_u64().max(1),
+        server_addr,
+        user_data: None,
+    };
+    let transport = NetcodeClientTransport::new(time, authentication, socket)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Ok((RenetClient::new(ConnectionConfig::default()), transport))
+}
+
+fn start_server(mut commands: Commands, cmds: Query<(Entity, &CmdNetServe)>) {
+    for (ent, cmd) in &cmds {
+        match new_server(cmd.addr) {
+            Ok((server, transport)) => {
+                println!("Serving on {}.", transport.addr());
+                commands.insert_resource(server);
+                commands.insert_resource(transport);
+            }
+            Err(e) => println!("Failed to serve on {}: {e}", cmd.addr),
+        }
+        commands.entity(ent).despawn();
+    }
+}
+
+fn start_client(mut commands: Commands, cmds: Query<(Entity, &CmdNetConnect)>, mut prediction: ResMut<Prediction>) {
+    for (ent, cmd) in &cmds {
+        *prediction = Prediction::default();
+        match new_client(cmd.addr) {
+            Ok((client, transport)) => {
+                println!("Connecting to {} as player {}.", cmd.addr, transport.client_id());
+                commands.insert_resource(client);
+                commands.insert_resource(transport);
+            }
+            Err(e) => println!("Failed to connect to {}: {e}", cmd.addr),
+        }
+        commands.entity(ent).despawn();
+    }
+}
+
+fn log_transport_errors(mut errors: EventReader<NetcodeTransportError>) {
+    for e in errors.iter() {
+        println!("Network error: {e}");
+    }
+}
+
+/// Gives every new client a player and removes the players of those who leave.
+fn server_connections(
+    mut commands: Commands,
+    mut events: EventReader<ServerEvent>,
+    mut server: ResMut<RenetServer>,
+    mut pending: ResMut<PendingLevels>,
+    remotes: Query<(Entity, &Remote)>,
+    lvl: Res<Level>,
+) {
+    for event in events.iter() {
+        match event {
+            ServerEvent::ClientConnected { client_id } => {
+                println!("Player {client_id} joined.");
+                pending.0.push(*client_id);
+                commands.spawn((
+                    Player { id: *client_id },
+                    Remote { id: *client_id },
+                    PlayerInput::default(),
+                    InputQueue::default(),
+                    player_body(Transform::from_translation(player_spawn(&lvl).unwrap_or(PLAYER_START))),
+                ));
+            }
+            ServerEvent::ClientDisconnected { client_id, reason } => {
+                println!("Player {client_id} left: {reason}");
+                for (ent, remote) in &remotes {
+                    if remote.id == *client_id {
+                        commands.entity(ent).despawn_recursive();
+                    }
+                }
+                server_send(&mut server, None, DefaultChannel::ReliableOrdered, &ServerMessage::PlayerLeft { id: *client_id });
+            }
+        }
+    }
+}
+
+/// Queues each client's input frames and hands one per tick to their player, where `player_movement` picks it up.
+/// When a client's queue runs dry their last input is kept.
+fn server_apply_input(mut server: ResMut<RenetServer>, mut players: Query<(&Remote, &mut InputQueue, &mut PlayerInput)>) {
+    for client_id in server.clients_id() {
+        while let Some(bytes) = server.receive_message(client_id, DefaultChannel::Unreliable) {
+            let Ok(ClientMessage::Input(frames)) = ClientMessage::decode(&bytes) else {
+                println!("Dropped a bad message from player {client_id}.");
+                continue;
+            };
+            let Some((_, mut queue, _)) = players.iter_mut().find(|(remote, ..)| remote.id == client_id) else {
+                continue;
+            };
+            for frame in frames {
+                if frame.tick > queue.received {
+                    queue.received = frame.tick;
+                    queue.frames.push_back(frame);
+                }
+            }
+            while queue.frames.len() > MAX_QUEUED_INPUTS {
+                queue.frames.pop_front();
+            }
+        }
+    }
+    for (_, mut queue, mut input) in &mut players {
+        if let Some(frame) = queue.frames.pop_front() {
+            *input = frame.limited();
+            queue.applied = frame.tick;
+        }
+    }
+}
+
+fn server_send_players(
+    mut server: ResMut<RenetServer>,
+    players: Query<(&Player, &Transform, &CharacterMovement, Option<&InputQueue>)>,
+) {
+    let states = players.iter().map(|(player, transform, movement, queue)| {
+        PlayerState {
+            id: player.id,
+            ack: queue.map_or(0, |q| q.applied),
+            translation: transform.translation,
+            rotation: transform.rotation,
+            velocity: movement.velocity,
+            vertical_velocity: movement.vertical_velocity,
+            grounded: movement.grounded,
+        }
+    }).collect();
+    server_send(&mut server, None, DefaultChannel::Unreliable, &ServerMessage::Players(states));
+}
+
+/// Runs tile edits asked for by clients through the usual tile commands. Edits out of the level or out of
+/// their player's reach are refused, and the client is sent the server's tiles to undo its prediction.
+fn server_receive_tiles(
+    mut commands: Commands,
+    mut server: ResMut<RenetServer>,
+    mut pending: ResMut<PendingLevels>,
+    lvl: Res<Level>,
+    players: Query<(&Remote, &Transform)>,
+) {
+    for client_id in server.clients_id() {
+        let player = players.iter().find(|(remote, _)| remote.id == client_id).map(|(_, t)| t.translation);
+        let mut undo = Vec::new();
+        while let Some(bytes) = server.receive_message(client_id, DefaultChannel::ReliableOrdered) {
+            let Ok(ClientMessage::Tiles(edits)) = ClientMessage::decode(&bytes) else {
+                println!("Dropped a bad message from player {client_id}.");
+                continue;
+            };
+            for edit in edits {
+                match player.and_then(|at| allowed_edit(edit, &lvl, at)) {
+                    Some(allowed) => {
+                        allowed.spawn_command(&mut commands);
+                    }
+                    None => match edit {
+                        TileEdit::Spawn { pos, .. } | TileEdit::Destroy { pos } => undo.push(match lvl.get(pos) {
+                            Some(mat) => TileEdit::Spawn { pos, mat },
+                            None => TileEdit::Destroy { pos },
+                        }),
+                        // Too many tiles to send back one by one.
+                        TileEdit::DestroyRect { .. } => if !pending.0.contains(&client_id) {
+                            pending.0.push(client_id);
+                        },
+                    },
+                }
+            }
+        }
+        if !undo.is_empty() {
+            server_send(&mut server, Some(client_id), DefaultChannel::ReliableOrdered, &ServerMessage::Tiles(undo));
+        }
+    }
+}
+
+/// The edit a client at `player` may make in place of `edit`, with rects clamped to the level,
+/// or `None` if it's outside the level, too big, or out of reach.
+fn allowed_edit(edit: TileEdit, lvl: &Level, player: Vec3) -> Option<TileEdit> {
+    let last = IVec3::new(lvl.size[0] as i32, lvl.size[1] as i32, lvl.size[2] as i32) - IVec3::ONE;
+    let (min, max) = match edit {
+        TileEdit::Spawn { pos, .. } | TileEdit::Destroy { pos } => (pos, pos),
+        TileEdit::DestroyRect { min, max } => (min.min(max).max(IVec3::ZERO), min.max(max).min(last)),
+    };
+    if min.cmplt(IVec3::ZERO).any() || max.cmpgt(last).any() || min.cmpgt(max).any() {
+        return None;
+    }
+    let extent = max - min + IVec3::ONE;
+    if extent.x as i64 * extent.y as i64 * extent.z as i64 > MAX_EDIT_RECT_TILES {
+        return None;
+    }
+    // Tiles are centred on multiples of `tile_scale`.
+    let half = Vec3::splat(0.5);
+    let nearest = player.clamp((min.as_vec3() - half) * lvl.tile_scale, (max.as_vec3() + half) * lvl.tile_scale);
+    if nearest.distance(player) > EDIT_REACH {
+        return None;
+    }
+    Some(match edit {
+        TileEdit::DestroyRect { .. } => TileEdit::DestroyRect { min, max },
+        edit => edit,
+    })
+}
+
+fn server_send_edits(mut server: ResMut<RenetServer>, mut edits: EventReader<TileEdit>) {
+    let edits: Vec<TileEdit> = edits.iter().copied().collect();
+    if !edits.is_empty() {
+        server_send(&mut server, None, DefaultChannel::ReliableOrdered, &ServerMessage::Tiles(edits));
+    }
+}
+
+/// Every client needs the new level once it has been generated or loaded.
+fn resend_level(server: Res<RenetServer>, mut pending: ResMut<PendingLevels>) {
+    pending.0 = server.clients_id();
+}
+
+/// Sends the level to clients that joined or missed a level change. Edits already sent to them
+/// on the same ordered channel are applied before it and then overwritten by it, so they end up
+/// with exactly the server's tiles.
+fn server_send_level(
+    mut server: ResMut<RenetServer>,
+    mut pending: ResMut<PendingLevels>,
+    lvl: Res<Level>,
+    mats: Res<MaterialTypes>,
+) {
+    if pending.0.is_empty() {
+        return;
+    }
+    let bytes = LevelSnapshot::capture(&lvl, &mats)
+        .and_then(|snapshot| snapshot.encode())
+        .and_then(|bytes| Ok(ServerMessage::Level(bytes).encode()?));
+    let bytes = match bytes {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            println!("Failed to capture the level for clients: {e}");
+            pending.0.clear();
+            return;
+        }
+    };
+    for client_id in pending.0.drain(..) {
+        if server.is_connected(client_id) {
+            server.send_message(client_id, DefaultChannel::ReliableOrdered, bytes.clone());
+        }
+    }
+}
+
+/// Stamps the local player's input with this tick and sends it to the server. The input stays in
+/// place so `player_movement` still acts on it here, predicting what the server will do.
+fn client_send_input(
+    mut client: ResMut<RenetClient>,
+    mut prediction: ResMut<Prediction>,
+    tick: Res<SimTick>,
+    inputs: Query<(&PlayerInput, &Transform, &CharacterMovement), With<NetLocal>>,
+) {
+    let Ok((input, transform, movement)) = inputs.get_single() else {
+        return;
+    };
+    let tick = tick.0;
+    prediction.history.push_back(Predicted {
+        tick,
+        input: *input,
+        rotation: transform.rotation,
+        translation: transform.translation,
+        movement: movement.clone(),
+    });
+    if prediction.history.len() > MAX_PREDICTED {
+        prediction.history.pop_front();
+    }
+    let frames = prediction.history.iter()
+        .rev()
+        .take(INPUT_REDUNDANCY)
+        .rev()
+        .map(|p| InputFrame { tick: p.tick, input: p.input })
+        .collect();
+    client_send(&mut client, DefaultChannel::Unreliable, &ClientMessage::Input(frames));
+}
+
+/// Remembers where this tick's physics left the local player, to compare with the server later.
+fn client_record_prediction(mut prediction: ResMut<Prediction>, locals: Query<(&Transform, &CharacterMovement), With<NetLocal>>) {
+    if let (Some(last), Ok((transform, movement))) = (prediction.history.back_mut(), locals.get_single()) {
+        last.translation = transform.translation;
+        last.movement = movement.clone();
+    }
+}
+
+/// Rewinds the local player to the server's newest state for them and replays the inputs the server
+/// hasn't applied yet through the character controller, before this tick is predicted on top.
+fn client_reconcile(
+    mut prediction: ResMut<Prediction>,
+    mut context: ResMut<RapierContext>,
+    time: Res<FixedTime>,
+    physics: Res<RapierConfiguration>,
+    mut locals: Query<(Entity, &mut Transform, &mut CharacterMovement, &KinematicCharacterController, &Collider), With<NetLocal>>,
+) {
+    let Some(state) = prediction.confirmed.take() else {
+        return;
+    };
+    let Ok((ent, mut transform, mut movement, controller, collider)) = locals.get_single_mut() else {
+        return;
+    };
+    let options = MoveShapeOptions {
+        up: controller.up,
+        offset: controller.offset,
+        slide: controller.slide,
+        autostep: controller.autostep,
+        max_slope_climb_angle: controller.max_slope_climb_angle,
+        min_slope_slide_angle: controller.min_slope_slide_angle,
+        apply_impulse_to_dynamic_bodies: false,
+        snap_to_ground: controller.snap_to_ground,
+    };
+    let filter = QueryFilter::from(controller.filter_flags).exclude_rigid_body(ent);
+    let rotation = transform.rotation;
+    let replayed = prediction.reconcile(&state, time.period.as_secs_f32(), physics.gravity.y, |from, by| {
+        let out = context.move_shape(by, collider, from, rotation, 0.0, &options, filter, |_| {});
+        (out.effective_translation, out.grounded)
+    });
+    if let Some((translation, replayed)) = replayed {
+        transform.translation = translation;
+        *movement = replayed;
+    }
+}
+
+/// Applies the server's view of every player, spawning players this client hasn't seen yet.
+/// The local player's state is kept for `client_reconcile` to check its prediction against.
+fn client_receive(
+    mut commands: Commands,
+    mut client: ResMut<RenetClient>,
+    transport: Res<NetcodeClientTransport>,
+    mut prediction: ResMut<Prediction>,
+    mut players: Query<(Entity, &mut Player, &mut Transform, &mut CharacterMovement, Option<&NetLocal>)>,
+    queued: QueuedTileCommands,
+) {
+    let own_id = transport.client_id();
+    let mut spawned = Vec::new();
+    // Tile commands not applied yet, which a new level makes stale.
+    let mut edits: Vec<Entity> = queued.iter().collect();
+    let mut messages = Vec::new();
+    while let Some(bytes) = client.receive_message(DefaultChannel::ReliableOrdered) {
+        messages.push(bytes);
+    }
+    while let Some(bytes) = client.receive_message(DefaultChannel::Unreliable) {
+        messages.push(bytes);
+    }
+    for bytes in messages {
+        match ServerMessage::decode(&bytes) {
+            Ok(ServerMessage::Players(states)) => {
+                for state in states {
+                    let found = players.iter_mut().find(|(_, player, _, _, local)| {
+                        if local.is_some() { state.id == own_id } else { player.id == state.id }
+                    });
+                    match found {
+                        Some((_, mut player, _, _, Some(_))) => {
+                            player.id = state.id;
+                            // Unreliable states can arrive out of order.
+                            if prediction.confirmed.is_none_or(|c| c.ack <= state.ack) {
+                                prediction.confirmed = Some(state);
+                            }
+                        }
+                        Some((_, mut player, mut transform, mut movement, None)) => {
+                            player.id = state.id;
+                            transform.translation = state.translation;
+                            transform.rotation = state.rotation;
+                            movement.velocity = state.velocity;
+                            movement.vertical_velocity = state.vertical_velocity;
+                            movement.grounded = state.grounded;
+                        }
+                        None if !spawned.contains(&state.id) => {
+                            spawned.push(state.id);
+                            let transform = Transform::from_translation(state.translation).with_rotation(state.rotation);
+                            commands.spawn((
+                                Player { id: state.id },
+                                Remote { id: state.id },
+                                player_body(transform),
+                            ));
+                        }
+                        None => {}
+                    }
+                }
+            }
+            Ok(ServerMessage::Level(bytes)) => match LevelSnapshot::decode(&bytes) {
+                Ok(snapshot) => {
+                    println!("Received the level from the server.");
+                    for ent in edits.drain(..) {
+                        commands.entity(ent).despawn();
+                    }
+                    commands.spawn(CmdLvlSnapshot { snapshot });
+                }
+                Err(e) => println!("Failed to read the level from the server: {e}"),
+            },
+            Ok(ServerMessage::Tiles(tiles)) => {
+                for edit in tiles {
+                    let ent = edit.spawn_command(&mut commands);
+                    commands.entity(ent).insert(Replicated);
+                    edits.push(ent);
+                }
+            }
+            Ok(ServerMessage::PlayerLeft { id }) => {
+                for (ent, player, _, _, local) in &players {
+                    if player.id == id && local.is_none() {
+                        commands.entity(ent).despawn_recursive();
+                    }
+                }
+            }
+            Err(e) => println!("Dropped a bad message from the server: {e}"),
+        }
+    }
+}
+
+/// Sends tile commands made on this client to the server. They are still applied here straight away,
+/// and the server's copy arriving later changes nothing unless another edit got there first.
+fn client_forward_tiles(
+    mut commands: Commands,
+    mut client: ResMut<RenetClient>,
+    spawns: Query<(Entity, &CmdSpawnTile), Without<Replicated>>,
+    destroys: Query<(Entity, &CmdDestroyTile), Without<Replicated>>,
+    rects: Query<(Entity, &CmdDestroyTileRect), Without<Replicated>>,
+) {
+    let mut edits = Vec::new();
+    for (ent, cmd) in &spawns {
+        edits.push(TileEdit::Spawn { pos: cmd.pos, mat: cmd.mat });
+        commands.entity(ent).insert(Replicated);
+    }
+    for (ent, cmd) in &destroys {
+        edits.push(TileEdit::Destroy { pos: cmd.pos });
+        commands.entity(ent).insert(Replicated);
+    }
+    for (ent, cmd) in &rects {
+        edits.push(TileEdit::DestroyRect { min: cmd.min, max: cmd.max });
+        commands.entity(ent).insert(Replicated);
+    }
+    if !edits.is_empty() {
+        client_send(&mut client, DefaultChannel::ReliableOrdered, &ClientMessage::Tiles(edits));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{character::walker, dungeon::LvlPlugin, material::stone};
+
+    fn net_app() -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(stone())
+            .init_resource::<SimTick>()
+            .init_resource::<RapierContext>()
+            .init_resource::<RapierConfiguration>()
+            .add_plugin(LvlPlugin)
+            .add_plugin(NetPlugin);
+        app
+    }
+
+    #[test]
+    fn loopback_client_joins_and_sees_the_server_player() {
+        let mut server = net_app();
+        server.world.spawn(CmdNetServe { addr: ([127, 0, 0, 1], 0).into() });
+        let host_at = Vec3::new(3.0, 4.0, 5.0);
+        server.world.spawn((Player { id: 0 }, player_body(Transform::from_translation(host_at))));
+        server.update();
+        let addr = server.world.resource::<NetcodeServerTransport>().addr();
+
+        let mut client = net_app();
+        client.world.spawn(CmdNetConnect { addr });
+        client.update();
+        let client_id = client.world.resource::<NetcodeClientTransport>().client_id();
+
+        let (mut host_seen, mut joined) = (None, false);
+        for _ in 0..500 {
+            server.update();
+            client.update();
+            host_seen = client.world.query::<(&Remote, &Transform)>()
+                .iter(&client.world)
+                .find(|(remote, _)| remote.id == 0)
+                .map(|(_, transform)| transform.translation);
+            joined = server.world.query::<&Remote>().iter(&server.world).any(|remote| remote.id == client_id);
+            if host_seen.is_some() && joined {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(host_seen, Some(host_at));
+        assert!(joined, "the server should spawn a player for the client");
+    }
+
+    /// Predicts a tick on open, flat ground the way the client does, facing -Z.
+    fn predict(prediction: &mut Prediction, movement: &mut CharacterMovement, translation: &mut Vec3, input: PlayerInput) {
+        let tick = prediction.history.back().map_or(1, |p| p.tick + 1);
+        movement.requested = input.movement.map(|m| Vec3::new(m.x, 0.0, -m.y));
+        movement.grounded = true;
+        *translation += step_character(movement, 1.0 / 60.0, -9.81) * Vec3::new(1.0, 0.0, 1.0);
+        prediction.history.push_back(Predicted {
+            tick,
+            input,
+            rotation: Quat::IDENTITY,
+            translation: *translation,
+            movement: movement.clone(),
+        });
+    }
+
+    fn on_flat_ground(_from: Vec3, by: Vec3) -> (Vec3, bool) {
+        (by * Vec3::new(1.0, 0.0, 1.0), true)
+    }
+
+    #[test]
+    fn reconcile_replays_unacknowledged_inputs_from_the_server_state() {
+        let forward = PlayerInput { movement: Some(Vec2::Y), ..default() };
+        let (mut prediction, mut movement, mut translation) = (Prediction::default(), walker(), Vec3::ZERO);
+        for _ in 0..10 {
+            predict(&mut prediction, &mut movement, &mut translation, forward);
+        }
+
+        // The server had the player blocked in place, standing still, after input 4.
+        let state = PlayerState {
+            id: 1,
+            ack: 4,
+            translation: Vec3::X,
+            rotation: Quat::IDENTITY,
+            velocity: Vec3::ZERO,
+            vertical_velocity: 0.0,
+            grounded: true,
+        };
+        let shifted = translation + state.translation - prediction.history[3].translation;
+        let (replayed, replayed_movement) = prediction.reconcile(&state, 1.0 / 60.0, -9.81, on_flat_ground).unwrap();
+
+        // Expected: six more inputs from a standstill, not the old prediction shifted.
+        let (mut expected, mut expected_movement, mut again) = (Vec3::X, walker(), Prediction::default());
+        for _ in 0..6 {
+            predict(&mut again, &mut expected_movement, &mut expected, forward);
+        }
+        assert!((replayed - expected).length() < 1e-4, "{replayed} != {expected}");
+        assert!((replayed_movement.velocity - expected_movement.velocity).length() < 1e-4);
+        assert!((replayed - shifted).length() > 0.01);
+        assert_eq!(prediction.history.front().map(|p| p.tick), Some(5));
+        assert_eq!(prediction.history.back().map(|p| p.translation), Some(replayed));
+    }
+
+    #[test]
+    fn reconcile_keeps_a_correct_prediction() {
+        let forward = PlayerInput { movement: Some(Vec2::Y), ..default() };
+        let (mut prediction, mut movement, mut translation) = (Prediction::default(), walker(), Vec3::ZERO);
+        for _ in 0..10 {
+            predict(&mut prediction, &mut movement, &mut translation, forward);
+        }
+        let confirmed = &prediction.history[3];
+        let state = PlayerState {
+            id: 1,
+            ack: 4,
+            translation: confirmed.translation,
+            rotation: Quat::IDENTITY,
+            velocity: confirmed.movement.velocity,
+            vertical_velocity: 0.0,
+            grounded: true,
+        };
+        assert!(prediction.reconcile(&state, 1.0 / 60.0, -9.81, on_flat_ground).is_none());
+        assert_eq!(prediction.history.len(), 6);
+        assert_eq!(prediction.history.back().map(|p| p.translation), Some(translation));
+    }
+
+    #[test]
+    fn allowed_edits_stay_in_the_level_and_in_reach() {
+        let lvl = Level { size: [8, 4, 8], tile_scale: 1.0, ..default() };
+        let at = Vec3::new(2.0, 1.0, 2.0);
+        let near = TileEdit::Spawn { pos: IVec3::new(3, 1, 2), mat: 0 };
+        assert_eq!(allowed_edit(near, &lvl, at), Some(near));
+        assert_eq!(allowed_edit(TileEdit::Destroy { pos: IVec3::new(2, 1, -1) }, &lvl, at), None);
+        assert_eq!(allowed_edit(TileEdit::Destroy { pos: IVec3::new(2, 4, 2) }, &lvl, at), None);
+        let far = Level { size: [64, 4, 64], ..lvl };
+        assert_eq!(allowed_edit(TileEdit::Destroy { pos: IVec3::new(20, 1, 2) }, &far, at), None);
+    }
+
+    #[test]
+    fn allowed_rects_are_clamped_to_the_level() {
+        let lvl = Level { size: [8, 4, 8], tile_scale: 1.0, ..default() };
+        let at = Vec3::new(2.0, 1.0, 2.0);
+        let rect = TileEdit::DestroyRect { min: IVec3::new(4, 9, 4), max: IVec3::new(-3, 0, -3) };
+        assert_eq!(allowed_edit(rect, &lvl, at), Some(TileEdit::DestroyRect { min: IVec3::ZERO, max: IVec3::new(4, 3, 4) }));
+        let outside = TileEdit::DestroyRect { min: IVec3::splat(-9), max: IVec3::splat(-1) };
+        assert_eq!(allowed_edit(outside, &lvl, at), None);
+        let huge = TileEdit::DestroyRect { min: IVec3::splat(i32::MIN), max: IVec3::splat(i32::MAX) };
+        let big = Level { size: [64, 64, 64], ..lvl };
+        assert_eq!(allowed_edit(huge, &big, at), None);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let edits = vec![
+            TileEdit::Spawn { pos: IVec3::new(1, -2, 3), mat: 7 },
+            TileEdit::Destroy { pos: IVec3::new(4, 5, 6) },
+            TileEdit::DestroyRect { min: IVec3::ZERO, max: IVec3::splat(2) },
+        ];
+        let server = ServerMessage::Tiles(edits.clone());
+        assert_eq!(ServerMessage::decode(&server.encode().unwrap()).unwrap(), server);
+        let client = ClientMessage::Tiles(edits);
+        assert_eq!(ClientMessage::decode(&client.encode().unwrap()).unwrap(), client);
+        let unsendable = ServerMessage::Tiles(vec![TileEdit::Spawn { pos: IVec3::ZERO, mat: u16::MAX as usize + 1 }]);
+        assert!(unsendable.encode().is_err());
+    }
+
+    #[test]
+    fn remote_input_is_finite_and_limited() {
+        let frame = |movement, aiming| InputFrame { tick: 1, input: PlayerInput { movement: Some(movement), aiming: Some(aiming), jump: false } };
+        let bad = ClientMessage::Input(vec![frame(Vec2::new(f32::NAN, 0.0), Vec2::ZERO)]);
+        assert!(ClientMessage::decode(&bad.encode().unwrap()).is_err());
+        let bad = ClientMessage::Input(vec![frame(Vec2::ZERO, Vec2::new(0.0, f32::INFINITY))]);
+        assert!(ClientMessage::decode(&bad.encode().unwrap()).is_err());
+
+        let limited = frame(Vec2::new(300.0, 400.0), Vec2::new(-1e9, 0.1)).limited();
+        assert!((limited.movement.unwrap() - Vec2::new(0.6, 0.8)).length() < 1e-6);
+        assert_eq!(limited.aiming, Some(Vec2::new(-MAX_AIM_PER_TICK, 0.1)));
+    }
+}
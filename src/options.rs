@@ -0,0 +1,251 @@
+use std::{fmt, net::SocketAddr, path::PathBuf};
+
+use bevy::{prelude::*, render::settings::{Backends, WgpuSettings}};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dungeon::{LEVEL_SIZE_X, LEVEL_SIZE_Y, LEVEL_SIZE_Z, MIN_LEVEL_SIZE},
+    generator::GeneratorConfig,
+    level_file::LEVEL_FILE_MAX_TILES,
+    net::DEFAULT_PORT,
+};
+
+pub const USAGE: &str = "\
+Usage: hexentropy [options]
+
+  --config <file>       read options from a RON file; later options override it
+  --seed <n>            level seed
+  --size <x>x<y>x<z>    level size in tiles
+  --tile-scale <f>      size of a tile in world units
+  --generator <g>       noise, bsp, caves, drunkard or wfc; a config file can set parameters too
+  --backend <b>         auto, vulkan or gl
+  --headless            run without a window or GPU
+  --window              run with a window (the default)
+  --mode <m>            offline, server, client or host
+  --addr <ip:port>      address to serve on or connect to
+  --record <file>       record the session's input
+  --replay <file>       play a recording back, then exit
+  --sensitivity <f>     degrees turned per pixel of mouse motion
+  --invert-y            look up when the mouse moves down
+  --bindings <file>     input bindings, written with the defaults if missing
+  --help                show this message";
+
+/// How the game is started, from the command line and an optional config file.
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameOptions {
+    pub seed: u64,
+    pub size: [usize; 3],
+    pub tile_scale: f32,
+    pub generator: GeneratorConfig,
+    pub backend: RenderBackend,
+    pub headless: bool,
+    pub mode: NetMode,
+    pub addr: SocketAddr,
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    /// Degrees the view turns per pixel of mouse motion.
+    pub sensitivity: f32,
+    pub invert_y: bool,
+    pub bindings: PathBuf,
+}
+
+impl Default for GameOptions {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            size: [LEVEL_SIZE_X, LEVEL_SIZE_Y, LEVEL_SIZE_Z],
+            tile_scale: 0.5,
+            generator: GeneratorConfig::default(),
+            backend: RenderBackend::default(),
+            headless: false,
+            mode: NetMode::default(),
+            addr: ([127, 0, 0, 1], DEFAULT_PORT).into(),
+            record: None,
+            replay: None,
+            sensitivity: 0.1,
+            invert_y: false,
+            bindings: "bindings.ron".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RenderBackend {
+    /// Whatever wgpu picks, or `WGPU_BACKEND` if set.
+    #[default]
+    Auto,
+    Vulkan,
+    Gl,
+}
+
+impl RenderBackend {
+    pub fn backends(self) -> Option<Backends> {
+        match self {
+            Self::Auto => WgpuSettings::default().backends,
+            Self::Vulkan => Some(Backends::VULKAN),
+            Self::Gl => Some(Backends::GL),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NetMode {
+    #[default]
+    Offline,
+    /// Serves `addr` without a local player.
+    Server,
+    /// Joins the server at `addr`.
+    Client,
+    /// Serves `addr` and plays too.
+    Host,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OptionsError {
+    /// `--help` was given.
+    Help,
+    MissingValue(String),
+    BadValue { option: String, value: String },
+    Unknown(String),
+    Config { path: PathBuf, error: String },
+    /// The options parsed but don't work together.
+    Invalid(String),
+}
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Help => write!(f, "{USAGE}"),
+            Self::MissingValue(option) => write!(f, "{option} needs a value"),
+            Self::BadValue { option, value } => write!(f, "bad value for {option}: {value}"),
+            Self::Unknown(arg) => write!(f, "unknown option {arg}"),
+            Self::Config { path, error } => write!(f, "failed to read {}: {error}", path.display()),
+            Self::Invalid(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for OptionsError {}
+
+impl GameOptions {
+    /// Parses arguments, not including the program name. A `--config` file is applied first,
+    /// wherever it appears, so the other options always win over it.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, OptionsError> {
+        let args: Vec<String> = args.into_iter().collect();
+        let mut options = match args.iter().position(|a| a == "--config") {
+            Some(i) => {
+                let path = PathBuf::from(args.get(i + 1).ok_or_else(|| OptionsError::MissingValue("--config".into()))?);
+                Self::from_config(&path)?
+            }
+            None => Self::default(),
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| OptionsError::MissingValue(arg.clone()));
+            match arg.as_str() {
+                "--help" | "-h" => return Err(OptionsError::Help),
+                "--config" => {
+                    value()?;
+                }
+                "--seed" => options.seed = parse(&arg, &value()?)?,
+                "--size" => options.size = parse_size(&arg, &value()?)?,
+                "--tile-scale" => options.tile_scale = parse(&arg, &value()?)?,
+                "--generator" => {
+                    let v = value()?;
+                    options.generator = GeneratorConfig::named(&v).ok_or(OptionsError::BadValue { option: arg, value: v })?;
+                }
+                "--backend" => {
+                    let v = value()?;
+                    options.backend = match v.as_str() {
+                        "auto" => RenderBackend::Auto,
+                        "vulkan" => RenderBackend::Vulkan,
+                        "gl" => RenderBackend::Gl,
+                        _ => return Err(OptionsError::BadValue { option: arg, value: v }),
+                    };
+                }
+                "--headless" => options.headless = true,
+                "--window" => options.headless = false,
+                "--mode" => {
+                    let v = value()?;
+                    options.mode = match v.as_str() {
+                        "offline" => NetMode::Offline,
+                        "server" => NetMode::Server,
+                        "client" => NetMode::Client,
+                        "host" => NetMode::Host,
+                        _ => return Err(OptionsError::BadValue { option: arg, value: v }),
+                    };
+                }
+                "--addr" => options.addr = parse(&arg, &value()?)?,
+                "--record" => options.record = Some(value()?.into()),
+                "--replay" => options.replay = Some(value()?.into()),
+                "--sensitivity" => options.sensitivity = parse(&arg, &value()?)?,
+                "--invert-y" => options.invert_y = true,
+                "--bindings" => options.bindings = value()?.into(),
+                _ => return Err(OptionsError::Unknown(arg)),
+            }
+        }
+        options.validate()?;
+        Ok(options)
+    }
+
+    /// Rejects combinations that would fail once the game is running.
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        if !self.tile_scale.is_finite() || self.tile_scale <= 0.0 {
+            return Err(OptionsError::Invalid(format!("tile scale {} must be a positive number", self.tile_scale)));
+        }
+        let [x, y, z] = self.size;
+        if self.size.iter().zip(MIN_LEVEL_SIZE).any(|(size, min)| *size < min) {
+            let [min_x, min_y, min_z] = MIN_LEVEL_SIZE;
+            return Err(OptionsError::Invalid(format!("level size {x}x{y}x{z} is smaller than {min_x}x{min_y}x{min_z}")));
+        }
+        // Bigger levels couldn't be saved, and their sizes could overflow.
+        if x.checked_mul(y).and_then(|n| n.checked_mul(z)).is_none_or(|n| n > LEVEL_FILE_MAX_TILES) {
+            return Err(OptionsError::Invalid(format!("level size {x}x{y}x{z} has more than {LEVEL_FILE_MAX_TILES} tiles")));
+        }
+        let size = IVec3::new(x as i32, y as i32, z as i32);
+        self.generator.validate(size).map_err(OptionsError::Invalid)
+    }
+
+    /// Reads options from a RON file; fields it leaves out keep their defaults.
+    pub fn from_config(path: &PathBuf) -> Result<Self, OptionsError> {
+        let config_error = |error: String| OptionsError::Config { path: path.clone(), error };
+        let bytes = std::fs::read(path).map_err(|e| config_error(e.to_string()))?;
+        ron::de::from_bytes(&bytes).map_err(|e| config_error(e.to_string()))
+    }
+}
+
+fn parse<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, OptionsError> {
+    value.parse().map_err(|_| OptionsError::BadValue { option: option.to_owned(), value: value.to_owned() })
+}
+
+fn parse_size(option: &str, value: &str) -> Result<[usize; 3], OptionsError> {
+    let parts = value.split('x').map(|p| parse(option, p)).collect::<Result<Vec<usize>, _>>()?;
+    <[usize; 3]>::try_from(parts).map_err(|_| OptionsError::BadValue { option: option.to_owned(), value: value.to_owned() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<GameOptions, OptionsError> {
+        GameOptions::from_args(line.split_whitespace().map(str::to_owned))
+    }
+
+    #[test]
+    fn tile_scale_must_be_positive() {
+        assert_eq!(args("--tile-scale 0.25").map(|o| o.tile_scale), Ok(0.25));
+        for bad in ["0", "-1", "NaN", "inf"] {
+            assert!(matches!(args(&format!("--tile-scale {bad}")), Err(OptionsError::Invalid(_))), "{bad}");
+        }
+    }
+
+    #[test]
+    fn size_must_fit_the_generators_and_the_level_file() {
+        assert_eq!(args("--size 8x4x8").map(|o| o.size), Ok([8, 4, 8]));
+        assert!(matches!(args("--size 7x4x8"), Err(OptionsError::Invalid(_))));
+        assert!(matches!(args("--size 64x0x64"), Err(OptionsError::Invalid(_))));
+        assert!(matches!(args("--size 65536x65536x65536"), Err(OptionsError::Invalid(_))));
+        assert!(matches!(args("--size 8x4"), Err(OptionsError::BadValue { .. })));
+    }
+}
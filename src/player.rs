@@ -1,7 +1,47 @@
-use bevy::{prelude::*, input::mouse::MouseMotion};
-use lerp::Lerp;
+use bevy::{prelude::*, window::{CursorGrabMode, PrimaryWindow}};
+use bevy_rapier3d::prelude::{RigidBody, Collider, KinematicCharacterController, CharacterAutostep, CharacterLength, QueryFilterFlags, Ccd, LockedAxes, Damping, Velocity, Sleeping, ColliderMassProperties, ExternalImpulse, Friction, ActiveEvents};
 
-use crate::character::CharacterMovement;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actions::{Action, ActionState, ActionSystem},
+    camera::PlayerCamera,
+    character::{CharacterHead, CharacterMovement},
+    dungeon::{Level, LvlState},
+    options::GameOptions,
+    raycast::PlayerView,
+    sim::SimSet,
+    GameAssets,
+};
+
+/// Samples the local player's actions, and moves every player from their `PlayerInput` each tick.
+/// Sampling only runs when bevy's input plugin is present, so headless apps can add this too.
+/// `Action::GrabCursor` grabs the cursor for looking around, without also digging or placing;
+/// `Action::ReleaseCursor` or losing focus lets it go.
+pub struct PlayerPlugin;
+
+impl Plugin for PlayerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems((player_input_move, player_input_aim)
+                .distributive_run_if(resource_exists::<Input<KeyCode>>())
+                .in_set(OnUpdate(LvlState::Ready)))
+            // Before gameplay reads the actions, so the grabbing click can be used up.
+            .add_system(grab_cursor
+                .run_if(resource_exists::<Input<KeyCode>>())
+                .run_if(in_state(LvlState::Ready))
+                .in_base_set(CoreSet::PreUpdate)
+                .after(ActionSystem))
+            .add_system(player_movement.in_base_set(SimSet::Input).in_schedule(CoreSchedule::FixedUpdate));
+    }
+}
+
+/// Where players appear when they join.
+pub const PLAYER_START: Vec3 = Vec3::new(16.0, 16.0, 4.0);
+/// Half the size of a player's capsule, which matches the `Player` mesh. Players fit through three-tile
+/// corridors and can jump in six-tile rooms at the default 0.5 tile scale.
+pub const PLAYER_HALF_EXTENTS: Vec3 = Vec3::new(0.4, 0.9, 0.4);
+/// Tallest ledge a player walks up without jumping: one tile, at the default 0.5 tile scale.
+pub const STEP_HEIGHT: f32 = 0.55;
 
 #[derive(Component)]
 pub struct Player {
@@ -14,87 +54,169 @@ pub struct Remote {
     pub id: u64,
 }
 
-pub enum PlayerInputMove {
-    Forward,
-    Backward,
-    Left,
-    Right,
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlayerInput {
+    pub movement: Option<Vec2>,
+    pub aiming: Option<Vec2>,
+    /// Jump was pressed since the last tick.
+    #[serde(default)]
+    pub jump: bool,
 }
 
-pub enum PlayerInputTurn {
-    Yaw(f32),
-    Pitch(f32),
+/// Physics shared by every player body, local or remote. `dress_players` adds the mesh when rendering.
+pub fn player_body(transform: Transform) -> impl Bundle {
+    (
+        SpatialBundle::from_transform(transform),
+        (
+            RigidBody::KinematicPositionBased,
+            KinematicCharacterController {
+                offset: CharacterLength::Absolute(0.02),
+                autostep: Some(CharacterAutostep {
+                    max_height: CharacterLength::Absolute(STEP_HEIGHT),
+                    min_width: CharacterLength::Absolute(0.2),
+                    include_dynamic_bodies: false,
+                }),
+                snap_to_ground: Some(CharacterLength::Absolute(STEP_HEIGHT)),
+                // Liquid, climbable and hazardous tiles are sensors to walk into, not walls.
+                filter_flags: QueryFilterFlags::EXCLUDE_SENSORS,
+                ..default()
+            },
+            CharacterMovement {
+                acceleration: 10.0,
+                friction: 12.0,
+                air_control: 0.2,
+                max_speed: 3.0,
+                jump_speed: 4.5,
+                ..default()
+            },
+            Collider::capsule_y(PLAYER_HALF_EXTENTS.y - PLAYER_HALF_EXTENTS.x, PLAYER_HALF_EXTENTS.x),
+            ColliderMassProperties::Mass(100.0),
+            Friction::coefficient(0.8),
+            Velocity::default(),
+            ExternalImpulse::default(),
+            Damping {
+                linear_damping: 1.0,
+                angular_damping: 0.0,
+            },
+            Sleeping::disabled(),
+            Ccd::enabled(),
+            LockedAxes::ROTATION_LOCKED_X | LockedAxes::ROTATION_LOCKED_Y,
+            ActiveEvents::COLLISION_EVENTS,
+        ),
+    )
 }
 
-#[derive(Component)]
-pub struct PlayerInput {
-    pub movement: Option<Vec2>,
-    pub aiming: Option<Vec2>,
+/// Where a player should appear in the level: standing on the floor of its `"Player"` spawn tile.
+pub fn player_spawn(lvl: &Level) -> Option<Vec3> {
+    let spawn = lvl.spawns.iter().find(|s| s.name == "Player")?;
+    let floor = (spawn.pos.as_vec3() - Vec3::Y * 0.5) * lvl.tile_scale;
+    Some(floor + Vec3::Y * (PLAYER_HALF_EXTENTS.y + 0.05))
+}
+
+/// Puts everyone at the level's spawn whenever a level is ready. `LvlPlugin` runs it, as it owns the level's states.
+pub fn move_players_to_spawn(lvl: Res<Level>, mut players: Query<(&mut Transform, &mut CharacterMovement), With<Player>>) {
+    let Some(spawn) = player_spawn(&lvl) else {
+        return;
+    };
+    for (mut transform, mut movement) in &mut players {
+        transform.translation = spawn;
+        movement.velocity = Vec3::ZERO;
+        movement.vertical_velocity = 0.0;
+    }
+}
+
+/// Gives new players their mesh, and the local player a head holding the camera. Headless runs leave them bare.
+pub fn dress_players(mut commands: Commands, assets: Res<GameAssets>, players: Query<(Entity, Option<&NetLocal>), Added<Player>>) {
+    let Some(mesh) = assets.meshes.get("Player") else {
+        return;
+    };
+    for (ent, local) in &players {
+        let mut player = commands.entity(ent);
+        player.insert((mesh.get_mesh(), mesh.get_material()));
+        if local.is_some() {
+            player.with_children(|parent| {
+                parent.spawn((
+                    SpatialBundle::from_transform(Transform::from_xyz(0.0, 0.65, 0.0)),
+                    CharacterHead::default(),
+                    PlayerView,
+                )).with_children(|head| {
+                    head.spawn((Camera3dBundle::default(), PlayerCamera));
+                });
+            });
+        }
+    }
 }
 
 pub fn player_input_move(
-    keys: Res<Input<KeyCode>>,
+    actions: Res<ActionState>,
     mut inputs: Query<&mut PlayerInput, With<NetLocal>>
 ) {
+    let mv = actions.axis(Action::Move);
     for mut input in &mut inputs {
-        println!("player_input_move");
-        let mut mv = Vec2::ZERO;
-        if keys.pressed(KeyCode::W) {
-            mv.y += 1.0;
-        }
-        if keys.pressed(KeyCode::S) {
-            mv.y -= 1.0;
-        }
-        if keys.pressed(KeyCode::A) {
-            mv.x -= 1.0;
-        }
-        if keys.pressed(KeyCode::D) {
-            mv.x += 1.0;
-        }
-        if mv != Vec2::ZERO {
-            println!("player_input_move");
-            input.movement = Some(mv);
+        // Held keys keep applying every tick until released.
+        input.movement = (mv != Vec2::ZERO).then_some(mv);
+        // A press waits for the next tick to pick it up.
+        if actions.just_pressed(Action::Jump) {
+            input.jump = true;
         }
     }
 }
 pub fn player_input_aim(
-    mut motions: EventReader<MouseMotion>,
-    mut inputs: Query<&mut PlayerInput, With<NetLocal>>
+    actions: Res<ActionState>,
+    mut inputs: Query<&mut PlayerInput, With<NetLocal>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    options: Res<GameOptions>,
 ) {
-    let mut summed_motions = Vec2::ZERO;
-    for motion in motions.iter() {
-        summed_motions += motion.delta;
-        println!("delta");
+    // The mouse only looks around while the window has it.
+    if windows.get_single().is_ok_and(|w| w.cursor.grab_mode == CursorGrabMode::None) {
+        return;
     }
+    // Looking right turns right, and looking up looks up unless inverted.
+    let look = actions.axis(Action::Look);
+    let flip = if options.invert_y { -1.0 } else { 1.0 };
+    let turn = Vec2::new(-look.x, look.y * flip) * options.sensitivity.to_radians();
     for mut input in &mut inputs {
-        println!("player_input_aim");
-        if summed_motions != Vec2::ZERO {
-            println!("player_input_aim");
-            input.aiming = Some(summed_motions * 2f32.to_radians());
+        if turn != Vec2::ZERO {
+            // Several frames of motion may pile up before the next tick consumes them.
+            input.aiming = Some(input.aiming.unwrap_or_default() + turn);
         }
     }
 }
 
+fn grab_cursor(
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+    mut actions: ResMut<ActionState>,
+) {
+    let Ok(mut window) = windows.get_single_mut() else {
+        return;
+    };
+    let grabbed = window.cursor.grab_mode != CursorGrabMode::None;
+    if grabbed && (actions.just_pressed(Action::ReleaseCursor) || !window.focused) {
+        window.cursor.grab_mode = CursorGrabMode::None;
+        window.cursor.visible = true;
+    } else if !grabbed && window.focused && actions.just_pressed(Action::GrabCursor) {
+        // macOS can only lock the cursor in place, and X11 and Windows can only confine it.
+        window.cursor.grab_mode = if cfg!(target_os = "macos") { CursorGrabMode::Locked } else { CursorGrabMode::Confined };
+        window.cursor.visible = false;
+        actions.consume_just_pressed();
+    }
+}
+
 pub fn player_movement(
     mut players: Query<(&mut PlayerInput, &mut CharacterMovement, &Transform)>
 ) {
     for (mut input, mut char, transform) in &mut players {
-        println!("player_movement");
-        match &input.movement {
-            Some(pim) => {
-                println!("player_movement (moving)");
-                char.requested = Some(transform.rotation * Vec3::new(pim.x, 0., pim.y));
-            }
-            None => {}
+        if let Some(pim) = &input.movement {
+            // Forward is -Z.
+            char.requested = Some(transform.rotation * Vec3::new(pim.x, 0., -pim.y));
+        }
+        if let Some(aim) = &input.aiming {
+            char.aim_requested = Some(*aim);
         }
-        match &input.aiming {
-            Some(aim) => {
-                println!("player_movement (aiming)");
-                char.aim_requested = Some(*aim);
-            }
-            None => {}
+        if input.jump {
+            char.jump_requested = true;
         }
-        input.movement = None;
         input.aiming = None;
+        input.jump = false;
     }
 }
\ No newline at end of file
@@ -0,0 +1,67 @@
+use bevy::{prelude::*, window::PrimaryWindow};
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::dungeon::{LvlProgress, LvlState};
+
+const TITLE: &str = "hexentropy";
+
+/// Draws level generation progress as a terminal progress bar, for the CLI and headless runs.
+pub struct ProgressBarPlugin;
+
+impl Plugin for ProgressBarPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(draw_progress_bar);
+    }
+}
+
+/// Shows level generation progress in the window title while the level is being built.
+pub struct ProgressTitlePlugin;
+
+impl Plugin for ProgressTitlePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(show_progress_in_title);
+    }
+}
+
+fn is_building(state: &State<LvlState>, progress: &LvlProgress) -> bool {
+    state.0 == LvlState::Generate && !progress.stage.is_empty() && progress.fraction < 1.0
+}
+
+fn draw_progress_bar(progress: Res<LvlProgress>, state: Res<State<LvlState>>, mut bar: Local<Option<ProgressBar>>) {
+    if !progress.is_changed() && !state.is_changed() {
+        return;
+    }
+    if !is_building(&state, &progress) {
+        if let Some(bar) = bar.take() {
+            bar.finish_and_clear();
+        }
+        return;
+    }
+    let bar = bar.get_or_insert_with(|| {
+        let bar = ProgressBar::new(100);
+        if let Ok(style) = ProgressStyle::with_template("{bar:40} {pos:>3}% {msg}") {
+            bar.set_style(style);
+        }
+        bar
+    });
+    bar.set_message(progress.stage.clone());
+    bar.set_position((progress.fraction * 100.0) as u64);
+}
+
+fn show_progress_in_title(
+    progress: Res<LvlProgress>,
+    state: Res<State<LvlState>>,
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+) {
+    if !progress.is_changed() && !state.is_changed() {
+        return;
+    }
+    let Ok(mut window) = windows.get_single_mut() else {
+        return;
+    };
+    window.title = if is_building(&state, &progress) {
+        format!("{TITLE} - {} {:.0}%", progress.stage, progress.fraction * 100.0)
+    } else {
+        TITLE.to_owned()
+    };
+}
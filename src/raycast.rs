@@ -0,0 +1,187 @@
+use bevy::{prelude::*, ecs::system::SystemParam};
+
+use crate::{dungeon::Level, material::MaterialTypes};
+
+/// How far the player can see tiles to pick them, in world units.
+pub const LOOK_DISTANCE: f32 = 8.0;
+
+/// Keeps `LookTarget` on the tile in the middle of the local player's view.
+pub struct RaycastPlugin;
+
+impl Plugin for RaycastPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LookTarget>()
+            .add_system(update_look_target);
+    }
+}
+
+/// Marks the camera the player looks through; rays for `LookTarget` start at it.
+#[derive(Component)]
+pub struct PlayerView;
+
+/// A tile struck by a ray.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileHit {
+    pub pos: IVec3,
+    /// Face the ray entered through, pointing back towards it, or zero if the ray started inside the tile.
+    pub normal: IVec3,
+    /// World units from the ray's origin to where it entered the tile.
+    pub distance: f32,
+}
+
+impl TileHit {
+    /// The empty tile in front of the face that was hit, where a new tile would go.
+    pub fn adjacent(&self) -> IVec3 {
+        self.pos + self.normal
+    }
+}
+
+/// The solid tile the local player is looking at, if one is within `LOOK_DISTANCE`.
+#[derive(Resource, Debug, Default)]
+pub struct LookTarget(pub Option<TileHit>);
+
+/// Walks the ray through the level's tiles in order (Amanatides and Woo's DDA) and returns the first
+/// whose material `hits` accepts. Tiles are centred on multiples of `tile_scale`, as they are meshed.
+pub fn raycast(
+    lvl: &Level,
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    hits: impl Fn(usize) -> bool,
+) -> Option<TileHit> {
+    let dir = direction.normalize_or_zero();
+    // Without a positive tile size or a finite distance the walk would never end.
+    if dir == Vec3::ZERO || !lvl.tile_scale.is_finite() || lvl.tile_scale <= 0.0 || !max_distance.is_finite() {
+        return None;
+    }
+    // In tile space each tile spans [pos, pos + 1).
+    let start = origin / lvl.tile_scale + Vec3::splat(0.5);
+    let mut pos = start.floor().as_ivec3();
+    let mut step = IVec3::ZERO;
+    // World distance along the ray to cross one tile, and to reach the next boundary, per axis.
+    let mut t_delta = Vec3::splat(f32::INFINITY);
+    let mut t_max = Vec3::splat(f32::INFINITY);
+    for axis in 0..3 {
+        if dir[axis] > 0.0 {
+            step[axis] = 1;
+            t_delta[axis] = lvl.tile_scale / dir[axis];
+            t_max[axis] = (pos[axis] as f32 + 1.0 - start[axis]) * t_delta[axis];
+        } else if dir[axis] < 0.0 {
+            step[axis] = -1;
+            t_delta[axis] = lvl.tile_scale / -dir[axis];
+            t_max[axis] = (start[axis] - pos[axis] as f32) * t_delta[axis];
+        }
+    }
+    let mut normal = IVec3::ZERO;
+    let mut distance = 0.0;
+    loop {
+        if lvl.get(pos).is_some_and(&hits) {
+            return Some(TileHit { pos, normal, distance });
+        }
+        let axis = if t_max.x <= t_max.y && t_max.x <= t_max.z {
+            0
+        } else if t_max.y <= t_max.z {
+            1
+        } else {
+            2
+        };
+        distance = t_max[axis];
+        if distance > max_distance {
+            return None;
+        }
+        pos[axis] += step[axis];
+        t_max[axis] += t_delta[axis];
+        normal = IVec3::ZERO;
+        normal[axis] = -step[axis];
+    }
+}
+
+/// Casts rays against the level's solid tiles from any system.
+#[derive(SystemParam)]
+pub struct TileRaycast<'w> {
+    lvl: Res<'w, Level>,
+    mats: Res<'w, MaterialTypes>,
+}
+
+impl TileRaycast<'_> {
+    pub fn cast(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<TileHit> {
+        raycast(&self.lvl, origin, direction, max_distance, |id| self.mats.is_solid(id))
+    }
+}
+
+fn update_look_target(mut target: ResMut<LookTarget>, views: Query<&GlobalTransform, With<PlayerView>>, tiles: TileRaycast) {
+    let hit = views.get_single().ok().and_then(|view| tiles.cast(view.translation(), view.forward(), LOOK_DISTANCE));
+    if target.0 != hit {
+        target.0 = hit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(tiles: &[IVec3]) -> Level {
+        let mut lvl = Level { tile_scale: 1.0, ..default() };
+        for pos in tiles {
+            lvl.set(*pos, Some(0));
+        }
+        lvl
+    }
+
+    fn cast(lvl: &Level, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<TileHit> {
+        raycast(lvl, origin, direction, max_distance, |_| true)
+    }
+
+    #[test]
+    fn axis_aligned_rays_stop_at_the_first_face() {
+        let lvl = level(&[IVec3::new(5, 0, 0), IVec3::new(7, 0, 0), IVec3::new(0, -4, 0)]);
+        let hit = cast(&lvl, Vec3::ZERO, Vec3::X, 10.0).unwrap();
+        assert_eq!((hit.pos, hit.normal, hit.distance), (IVec3::new(5, 0, 0), IVec3::NEG_X, 4.5));
+        assert_eq!(hit.adjacent(), IVec3::new(4, 0, 0));
+        let hit = cast(&lvl, Vec3::ZERO, Vec3::NEG_Y * 3.0, 10.0).unwrap();
+        assert_eq!((hit.pos, hit.normal, hit.distance), (IVec3::new(0, -4, 0), IVec3::Y, 3.5));
+        assert_eq!(cast(&lvl, Vec3::ZERO, Vec3::NEG_X, 10.0), None);
+    }
+
+    #[test]
+    fn diagonal_rays_visit_the_tiles_they_cross() {
+        // Along y = x + 0.1, which crosses into (3, 3) through its -X face and passes (4, 2) by.
+        let lvl = level(&[IVec3::new(4, 2, 0), IVec3::new(3, 3, 0)]);
+        let hit = cast(&lvl, Vec3::new(0.0, 0.1, 0.0), Vec3::new(1.0, 1.0, 0.0), 10.0).unwrap();
+        assert_eq!((hit.pos, hit.normal), (IVec3::new(3, 3, 0), IVec3::NEG_X));
+        assert!((hit.distance - 2.5 * std::f32::consts::SQRT_2).abs() < 1e-5);
+        let skipped = level(&[IVec3::new(4, 2, 0)]);
+        assert_eq!(cast(&skipped, Vec3::new(0.0, 0.1, 0.0), Vec3::new(1.0, 1.0, 0.0), 10.0), None);
+    }
+
+    #[test]
+    fn rays_starting_on_a_boundary_belong_to_the_tile_ahead() {
+        let lvl = level(&[IVec3::ZERO]);
+        // x = 0.5 is where tile 0 ends; looking back into it hits it straight away.
+        let hit = cast(&lvl, Vec3::new(0.5, 0.0, 0.0), Vec3::NEG_X, 10.0).unwrap();
+        assert_eq!((hit.pos, hit.normal, hit.distance), (IVec3::ZERO, IVec3::X, 0.0));
+        assert_eq!(cast(&lvl, Vec3::new(0.5, 0.0, 0.0), Vec3::X, 10.0), None);
+        // Starting inside a tile hits it with no face.
+        let hit = cast(&lvl, Vec3::new(-0.5, 0.0, 0.0), Vec3::X, 10.0).unwrap();
+        assert_eq!((hit.pos, hit.normal, hit.distance), (IVec3::ZERO, IVec3::ZERO, 0.0));
+    }
+
+    #[test]
+    fn rays_give_up_past_max_distance() {
+        let lvl = level(&[IVec3::new(5, 0, 0)]);
+        assert_eq!(cast(&lvl, Vec3::ZERO, Vec3::X, 4.4), None);
+        assert_eq!(cast(&lvl, Vec3::ZERO, Vec3::X, 4.5).map(|hit| hit.pos), Some(IVec3::new(5, 0, 0)));
+        assert_eq!(cast(&level(&[]), Vec3::ZERO, Vec3::new(1.0, 2.0, 3.0), 50.0), None);
+    }
+
+    #[test]
+    fn rays_need_a_direction_and_a_positive_tile_scale() {
+        let mut lvl = level(&[IVec3::new(5, 0, 0)]);
+        assert_eq!(cast(&lvl, Vec3::ZERO, Vec3::ZERO, 10.0), None);
+        assert_eq!(cast(&lvl, Vec3::ZERO, Vec3::X, f32::INFINITY), None);
+        for scale in [0.0, -1.0, f32::NAN] {
+            lvl.tile_scale = scale;
+            assert_eq!(cast(&lvl, Vec3::ZERO, Vec3::X, 10.0), None);
+        }
+    }
+}
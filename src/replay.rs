@@ -0,0 +1,405 @@
+use std::path::PathBuf;
+
+use bevy::{prelude::*, app::AppExit};
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    character::CharacterMovement,
+    dungeon::{CmdDestroyTile, CmdDestroyTileRect, CmdLvlInit, CmdSpawnTile, Level, LvlSet, TileEdit},
+    generator::GeneratorConfig,
+    player::{player_movement, NetLocal, PlayerInput},
+    sim::{SimSet, SimTick},
+};
+
+/// Process exit code when a replay doesn't end where its recording did.
+pub const DIVERGED_EXIT_CODE: i32 = 1;
+
+/// Records the local player's input every tick to a RON file, and plays such files back.
+/// Since ticks are deterministic, playback ends where the recording did; if not, the app exits with
+/// `DIVERGED_EXIT_CODE`.
+pub struct ReplayPlugin;
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        // The recorder or playback must exist by the time a level started the same frame is seen.
+        app.add_systems((start_recording, start_playback, apply_system_buffers, replay_level_init)
+                .chain()
+                .before(LvlSet::Edit))
+            .add_system(save_recording.run_if(resource_exists::<ReplayRecorder>()).in_base_set(CoreSet::Last))
+            .add_system(exit_diverged
+                .run_if(resource_exists::<ReplayPlayback>())
+                .after(save_recording)
+                .in_base_set(CoreSet::Last))
+            .add_systems((
+                record_tick.run_if(resource_exists::<ReplayRecorder>()),
+                play_tick.run_if(resource_exists::<ReplayPlayback>()),
+            ).before(player_movement).in_base_set(SimSet::Input).in_schedule(CoreSchedule::FixedUpdate))
+            .add_system(finish_playback
+                .run_if(resource_exists::<ReplayPlayback>())
+                .in_base_set(SimSet::Late)
+                .in_schedule(CoreSchedule::FixedUpdate));
+    }
+}
+
+/// Starts recording; the file is written when the app exits.
+#[derive(Component)]
+pub struct CmdReplayRecord {
+    pub path: PathBuf,
+}
+
+/// Plays a recording back in place of the local player's input, then exits.
+#[derive(Component)]
+pub struct CmdReplayPlay {
+    pub path: PathBuf,
+}
+
+/// A session from the start of its level: how the level was generated and each tick's input.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Replay {
+    pub seed: u64,
+    pub generator: GeneratorConfig,
+    /// Level size in tiles and tile scale, which playback uses in place of its own options.
+    #[serde(default)]
+    pub size: Option<[usize; 3]>,
+    #[serde(default)]
+    pub tile_scale: Option<f32>,
+    /// The local player when the first tick ran.
+    pub start: ReplayPose,
+    pub ticks: Vec<ReplayTick>,
+    /// How the session ended, for playback to check against.
+    pub end: Option<ReplayEnd>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReplayPose {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub velocity: Vec3,
+    #[serde(default)]
+    pub vertical_velocity: f32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReplayTick {
+    pub input: PlayerInput,
+    /// Tile commands applied this tick.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub edits: Vec<TileEdit>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReplayEnd {
+    pub pose: ReplayPose,
+    /// `level_hash` of the final level.
+    pub level: u64,
+}
+
+#[derive(Resource)]
+struct ReplayRecorder {
+    path: PathBuf,
+    replay: Replay,
+}
+
+#[derive(Resource)]
+struct ReplayPlayback {
+    replay: Replay,
+    /// `SimTick` of the first tick played, which plays `replay.ticks[0]`.
+    first: Option<u64>,
+    done: bool,
+    diverged: bool,
+}
+
+impl ReplayPose {
+    fn of(transform: &Transform, movement: &CharacterMovement) -> Self {
+        Self {
+            translation: transform.translation,
+            rotation: transform.rotation,
+            velocity: movement.velocity,
+            vertical_velocity: movement.vertical_velocity,
+        }
+    }
+}
+
+/// FNV-1a over every tile in position order, so equal levels hash equally however they were built.
+pub fn level_hash(lvl: &Level) -> u64 {
+    let mut tiles: Vec<_> = lvl.iter().map(|(pos, id)| (pos.to_array(), id)).collect();
+    tiles.sort_unstable();
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for (pos, id) in tiles {
+        let bytes = pos.iter().flat_map(|c| c.to_le_bytes()).chain((id as u64).to_le_bytes());
+        for b in bytes {
+            hash = (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+fn start_recording(mut commands: Commands, records: Query<(Entity, &CmdReplayRecord)>) {
+    for (ent, record) in &records {
+        commands.entity(ent).despawn();
+        println!("Recording to {}.", record.path.display());
+        commands.insert_resource(ReplayRecorder {
+            path: record.path.clone(),
+            replay: Replay::default(),
+        });
+    }
+}
+
+fn start_playback(mut commands: Commands, plays: Query<(Entity, &CmdReplayPlay)>, mut ev_app: EventWriter<AppExit>) {
+    for (ent, play) in &plays {
+        commands.entity(ent).despawn();
+        let replay = std::fs::read(&play.path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| ron::de::from_bytes::<Replay>(&bytes).map_err(|e| e.to_string()));
+        match replay {
+            Ok(replay) => {
+                println!("Playing {} ticks from {}.", replay.ticks.len(), play.path.display());
+                commands.insert_resource(ReplayPlayback { replay, first: None, done: false, diverged: false });
+            }
+            Err(e) => {
+                println!("Failed to read replay {}: {e}", play.path.display());
+                ev_app.send(AppExit);
+            }
+        }
+    }
+}
+
+/// A new level restarts the recording, and playback swaps in the recorded level.
+fn replay_level_init(
+    mut inits: Query<&mut CmdLvlInit>,
+    mut lvl: ResMut<Level>,
+    recorder: Option<ResMut<ReplayRecorder>>,
+    playback: Option<Res<ReplayPlayback>>,
+) {
+    let Some(mut init) = inits.iter_mut().last() else {
+        return;
+    };
+    if let Some(playback) = playback {
+        let replay = &playback.replay;
+        init.seed = replay.seed;
+        init.generator = replay.generator.clone();
+        if let Some(size) = replay.size {
+            lvl.size = size;
+        }
+        if let Some(tile_scale) = replay.tile_scale {
+            lvl.tile_scale = tile_scale;
+        }
+    }
+    if let Some(mut recorder) = recorder {
+        recorder.replay = Replay {
+            seed: init.seed,
+            generator: init.generator.clone(),
+            size: Some(lvl.size),
+            tile_scale: Some(lvl.tile_scale),
+            ..default()
+        };
+    }
+}
+
+fn record_tick(
+    mut recorder: ResMut<ReplayRecorder>,
+    players: Query<(&PlayerInput, &Transform, &CharacterMovement), With<NetLocal>>,
+    spawns: Query<&CmdSpawnTile>,
+    destroys: Query<&CmdDestroyTile>,
+    rects: Query<&CmdDestroyTileRect>,
+) {
+    let Ok((input, transform, movement)) = players.get_single() else {
+        return;
+    };
+    let replay = &mut recorder.replay;
+    if replay.ticks.is_empty() {
+        replay.start = ReplayPose::of(transform, movement);
+    }
+    let edits = spawns.iter().map(|c| TileEdit::Spawn { pos: c.pos, mat: c.mat })
+        .chain(destroys.iter().map(|c| TileEdit::Destroy { pos: c.pos }))
+        .chain(rects.iter().map(|c| TileEdit::DestroyRect { min: c.min, max: c.max }))
+        .collect();
+    replay.ticks.push(ReplayTick { input: *input, edits });
+}
+
+fn save_recording(
+    mut ev_app: EventReader<AppExit>,
+    mut recorder: ResMut<ReplayRecorder>,
+    players: Query<(&Transform, &CharacterMovement), With<NetLocal>>,
+    lvl: Res<Level>,
+) {
+    if ev_app.iter().last().is_none() {
+        return;
+    }
+    if let Ok((transform, movement)) = players.get_single() {
+        recorder.replay.end = Some(ReplayEnd {
+            pose: ReplayPose::of(transform, movement),
+            level: level_hash(&lvl),
+        });
+    }
+    let config = PrettyConfig::new().depth_limit(2);
+    let result = ron::ser::to_string_pretty(&recorder.replay, config)
+        .map_err(|e| e.to_string())
+        .and_then(|text| std::fs::write(&recorder.path, text).map_err(|e| e.to_string()));
+    match result {
+        Ok(()) => println!("Saved {} ticks to {}.", recorder.replay.ticks.len(), recorder.path.display()),
+        Err(e) => println!("Failed to save replay to {}: {e}", recorder.path.display()),
+    }
+}
+
+/// Hands the next recorded tick to the local player, along with the tile commands it applied.
+fn play_tick(
+    mut commands: Commands,
+    mut playback: ResMut<ReplayPlayback>,
+    tick: Res<SimTick>,
+    mut players: Query<(&mut PlayerInput, &mut Transform, &mut CharacterMovement), With<NetLocal>>,
+) {
+    let Ok((mut input, mut transform, mut movement)) = players.get_single_mut() else {
+        return;
+    };
+    if playback.done {
+        *input = PlayerInput::default();
+        return;
+    }
+    let first = *playback.first.get_or_insert(tick.0);
+    let next = (tick.0 - first) as usize;
+    if next == 0 {
+        let start = playback.replay.start;
+        transform.translation = start.translation;
+        transform.rotation = start.rotation;
+        movement.velocity = start.velocity;
+        movement.vertical_velocity = start.vertical_velocity;
+    }
+    let Some(recorded) = playback.replay.ticks.get(next) else {
+        return;
+    };
+    *input = recorded.input;
+    for edit in &recorded.edits {
+        edit.spawn_command(&mut commands);
+    }
+}
+
+/// Once every tick has run, compares the result with the recording's and exits.
+fn finish_playback(
+    mut playback: ResMut<ReplayPlayback>,
+    tick: Res<SimTick>,
+    players: Query<(&Transform, &CharacterMovement), With<NetLocal>>,
+    lvl: Res<Level>,
+    mut ev_app: EventWriter<AppExit>,
+) {
+    let played = playback.first.map_or(0, |first| (tick.0 + 1 - first) as usize);
+    if playback.done || played < playback.replay.ticks.len() {
+        return;
+    }
+    let Ok((transform, movement)) = players.get_single() else {
+        return;
+    };
+    playback.done = true;
+    let end = ReplayEnd {
+        pose: ReplayPose::of(transform, movement),
+        level: level_hash(&lvl),
+    };
+    match playback.replay.end {
+        Some(recorded) if recorded == end => println!("Replay matched the recording."),
+        Some(recorded) => {
+            println!("Replay diverged: recorded {recorded:?}, played {end:?}.");
+            playback.diverged = true;
+        }
+        None => println!("Replay finished with {end:?}; the recording has no end to compare."),
+    }
+    ev_app.send(AppExit);
+}
+
+/// Exits with `DIVERGED_EXIT_CODE` as the app closes after a playback that diverged, so scripts can tell.
+fn exit_diverged(mut ev_app: EventReader<AppExit>, playback: Res<ReplayPlayback>) {
+    if ev_app.iter().last().is_some() && playback.diverged {
+        std::process::exit(DIVERGED_EXIT_CODE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::scene::ScenePlugin;
+
+    use super::*;
+    use crate::{
+        character::CharacterPlugin,
+        dungeon::{LvlPlugin, LvlState},
+        material::dungeon_materials,
+        player::{player_body, Player, PlayerPlugin, PLAYER_START},
+        sim::SimPlugin,
+    };
+
+    fn sim_app() -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(AssetPlugin::default())
+            .add_plugin(TransformPlugin)
+            .add_plugin(HierarchyPlugin)
+            .add_asset::<Mesh>()
+            .add_plugin(ScenePlugin)
+            .insert_resource(dungeon_materials())
+            .add_plugin(SimPlugin::default())
+            .add_plugin(CharacterPlugin)
+            .add_plugin(PlayerPlugin)
+            .add_plugin(LvlPlugin)
+            .add_plugin(ReplayPlugin);
+        app.world.spawn((Player { id: 0 }, PlayerInput::default(), NetLocal, player_body(Transform::from_translation(PLAYER_START))));
+        app
+    }
+
+    /// Updates until `done` holds, failing after a while.
+    fn update_until(app: &mut App, done: impl Fn(&mut World) -> bool) {
+        for _ in 0..5000 {
+            app.update();
+            if done(&mut app.world) {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        panic!("timed out");
+    }
+
+    fn ready(world: &mut World) -> bool {
+        world.resource::<State<LvlState>>().0 == LvlState::Ready
+    }
+
+    #[test]
+    fn playback_ends_where_the_recording_did() {
+        let path = std::env::temp_dir().join(format!("hexentropy-replay-{}.ron", std::process::id()));
+
+        let mut app = sim_app();
+        app.world.resource_mut::<Level>().size = [32, 8, 32];
+        app.world.spawn(CmdReplayRecord { path: path.clone() });
+        app.world.spawn(CmdLvlInit { seed: 7, ..default() });
+        update_until(&mut app, ready);
+        let solid = app.world.resource::<Level>().iter().map(|(pos, _)| pos).max_by_key(|pos| pos.to_array()).unwrap();
+        for tick in 0..120 {
+            let mut players = app.world.query_filtered::<&mut PlayerInput, With<NetLocal>>();
+            *players.single_mut(&mut app.world) = PlayerInput {
+                movement: Some(Vec2::new(0.3, 1.0)),
+                aiming: Some(Vec2::new(0.01, 0.0)),
+                jump: tick % 40 == 0,
+            };
+            if tick == 60 {
+                app.world.spawn(CmdDestroyTile { pos: solid });
+            }
+            app.world.run_schedule(CoreSchedule::FixedUpdate);
+        }
+        app.world.send_event(AppExit);
+        app.update();
+        let recorded: Replay = ron::de::from_bytes(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(recorded.size, Some([32, 8, 32]));
+        assert!(recorded.ticks.iter().any(|t| t.edits == [TileEdit::Destroy { pos: solid }]));
+        let end = recorded.end.unwrap();
+        assert_ne!(end.pose.translation, recorded.start.translation);
+
+        // Playback swaps in the recorded size along with the seed.
+        let mut app = sim_app();
+        app.world.spawn(CmdReplayPlay { path: path.clone() });
+        app.world.spawn(CmdLvlInit::default());
+        update_until(&mut app, |world| world.resource::<ReplayPlayback>().done);
+        std::fs::remove_file(&path).ok();
+
+        let playback = app.world.resource::<ReplayPlayback>();
+        assert!(!playback.diverged);
+        assert_eq!(app.world.resource::<Level>().size, [32, 8, 32]);
+        assert_eq!(app.world.resource::<Level>().get(solid), None);
+    }
+}
@@ -0,0 +1,85 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::{NoUserData, PhysicsSet, RapierConfiguration, RapierPhysicsPlugin, TimestepMode};
+
+use crate::dungeon::LvlState;
+
+pub const DEFAULT_TICK_RATE: f64 = 60.0;
+const PHYSICS_SCALE: f32 = 0.5;
+
+/// Runs gameplay and physics in `CoreSchedule::FixedUpdate` at `tick_rate` ticks per second,
+/// so the same inputs always give the same results whatever the frame rate.
+pub struct SimPlugin {
+    pub tick_rate: f64,
+}
+
+impl Default for SimPlugin {
+    fn default() -> Self {
+        Self { tick_rate: DEFAULT_TICK_RATE }
+    }
+}
+
+/// Stages of one simulation tick, in order. Rapier's physics sets follow `Gameplay` and precede `Late`.
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[system_set(base)]
+pub enum SimSet {
+    /// Turns player input into character requests.
+    Input,
+    /// Applies commands spawned during `Input`, so tile commands take effect the same tick.
+    InputFlush,
+    /// Moves characters and edits tiles.
+    Gameplay,
+    /// Sees the result of the tick's physics step.
+    Late,
+}
+
+/// Ticks simulated since the app started, counting from 1 with the first. Ticks while no level is
+/// ready aren't simulated and don't count, so a session's ticks are numbered without gaps.
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimTick(pub u64);
+
+impl Plugin for SimPlugin {
+    fn build(&self, app: &mut App) {
+        let dt = 1.0 / self.tick_rate;
+        app.insert_resource(FixedTime::new_from_secs(dt as f32))
+            .init_resource::<SimTick>()
+            .add_plugin(RapierPhysicsPlugin::<NoUserData>::default()
+                .with_physics_scale(PHYSICS_SCALE)
+                .with_default_system_setup(false));
+        app.world.get_resource_or_insert_with(RapierConfiguration::default).timestep_mode = TimestepMode::Fixed {
+            dt: dt as f32,
+            substeps: 1,
+        };
+
+        let ready = || in_state(LvlState::Ready);
+        app.edit_schedule(CoreSchedule::FixedUpdate, |schedule| {
+            schedule.configure_sets((
+                SimSet::Input.run_if(ready()),
+                SimSet::InputFlush.run_if(ready()),
+                SimSet::Gameplay.run_if(ready()),
+                PhysicsSet::SyncBackend.run_if(ready()),
+                PhysicsSet::SyncBackendFlush.run_if(ready()),
+                PhysicsSet::StepSimulation.run_if(ready()),
+                PhysicsSet::Writeback.run_if(ready()),
+                SimSet::Late.run_if(ready()),
+            ).chain());
+        });
+        app.add_system(advance_tick.run_if(ready()).before(SimSet::Input).in_schedule(CoreSchedule::FixedUpdate))
+            .add_system(apply_system_buffers.in_base_set(SimSet::InputFlush).in_schedule(CoreSchedule::FixedUpdate))
+            .add_systems(RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::SyncBackend)
+                .in_base_set(PhysicsSet::SyncBackend)
+                .in_schedule(CoreSchedule::FixedUpdate))
+            .add_systems(RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::SyncBackendFlush)
+                .in_base_set(PhysicsSet::SyncBackendFlush)
+                .in_schedule(CoreSchedule::FixedUpdate))
+            .add_systems(RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::StepSimulation)
+                .in_base_set(PhysicsSet::StepSimulation)
+                .in_schedule(CoreSchedule::FixedUpdate))
+            .add_systems(RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::Writeback)
+                .in_base_set(PhysicsSet::Writeback)
+                .in_schedule(CoreSchedule::FixedUpdate));
+    }
+}
+
+fn advance_tick(mut tick: ResMut<SimTick>) {
+    tick.0 += 1;
+}